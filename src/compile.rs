@@ -0,0 +1,425 @@
+use crate::format;
+
+// Variables live in memory from here up, clear of programs assembled into
+// the bottom of memory in von Neumann mode.
+const VARIABLE_BASE: usize = 128;
+// R0 to R12 hold intermediate values.
+const REGISTERS: usize = 13;
+
+const SYMBOLS: [&str; 19] = [
+    "==", "!=", "<=", ">=", "<<", ">>", "<", ">", "=", "+", "-", "&", "|", "^", "(", ")", "{", "}", ";",
+];
+const RELATIONS: [&str; 6] = ["==", "!=", "<", ">", "<=", ">="];
+// Binary operators from loosest to tightest binding, as in C.
+const PRECEDENCE: [&[&str]; 5] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"]];
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Number(i32),
+    Name(String),
+    Symbol(&'static str),
+}
+
+enum Expr {
+    Number(i32),
+    Variable(String),
+    Negate(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+struct Condition {
+    left: Expr,
+    relation: &'static str,
+    right: Expr,
+}
+
+enum Statement {
+    Assign(String, Expr),
+    If(Condition, Vec<Statement>, Vec<Statement>),
+    While(Condition, Vec<Statement>),
+}
+
+// Tokens with the 1-based source line each came from. `//` starts a comment.
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = vec![];
+    for (index, line) in source.lines().enumerate() {
+        let mut rest = line.split("//").next().unwrap().trim_start();
+        while !rest.is_empty() {
+            let length;
+            if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+                tokens.push((Token::Symbol(symbol), index + 1));
+                length = symbol.len();
+            } else if rest.starts_with(|c: char| c.is_ascii_digit()) {
+                length = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                let number = rest[..length]
+                    .parse()
+                    .map_err(|_| format!("source line {}: number '{}' is too large", index + 1, &rest[..length]))?;
+                tokens.push((Token::Number(number), index + 1));
+            } else if rest.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+                length = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+                tokens.push((Token::Name(rest[..length].to_string()), index + 1));
+            } else {
+                return Err(format!("source line {}: unexpected character '{}'", index + 1, rest.chars().next().unwrap()));
+            }
+            rest = rest[length..].trim_start();
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn error(&self, message: &str) -> String {
+        match self.tokens.get(self.position) {
+            Some((_, line)) => format!("source line {}: {}", line, message),
+            None => format!("end of source: {}", message),
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Symbol(found)) if *found == symbol);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        if self.eat(symbol) { Ok(()) } else { Err(self.error(&format!("expected '{}'", symbol))) }
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, String> {
+        self.expect("{")?;
+        let mut statements = vec![];
+        while !self.eat("}") {
+            if self.peek().is_none() {
+                return Err(self.error("expected '}'"));
+            }
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Statement, String> {
+        match self.next() {
+            Some(Token::Name(keyword)) if keyword == "if" => {
+                let condition = self.condition()?;
+                let then = self.block()?;
+                let mut otherwise = vec![];
+                if self.peek() == Some(&Token::Name(String::from("else"))) {
+                    self.position += 1;
+                    if self.peek() == Some(&Token::Name(String::from("if"))) {
+                        otherwise.push(self.statement()?);
+                    } else {
+                        otherwise = self.block()?;
+                    }
+                }
+                Ok(Statement::If(condition, then, otherwise))
+            }
+            Some(Token::Name(keyword)) if keyword == "while" => {
+                let condition = self.condition()?;
+                Ok(Statement::While(condition, self.block()?))
+            }
+            Some(Token::Name(variable)) if variable != "else" => {
+                self.expect("=")?;
+                let value = self.expr(0)?;
+                self.expect(";")?;
+                Ok(Statement::Assign(variable, value))
+            }
+            _ => {
+                self.position -= 1;
+                Err(self.error("expected a statement"))
+            }
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let left = self.expr(0)?;
+        let relation = match self.next() {
+            Some(Token::Symbol(symbol)) if RELATIONS.contains(&symbol) => symbol,
+            _ => {
+                self.position -= 1;
+                return Err(self.error("expected a comparison"));
+            }
+        };
+        let right = self.expr(0)?;
+        Ok(Condition { left, relation, right })
+    }
+
+    fn expr(&mut self, level: usize) -> Result<Expr, String> {
+        let Some(operators) = PRECEDENCE.get(level) else {
+            return self.unary();
+        };
+        let mut left = self.expr(level + 1)?;
+        while let Some(Token::Symbol(symbol)) = self.peek()
+            && operators.contains(symbol)
+        {
+            let symbol = *symbol;
+            self.position += 1;
+            left = Expr::Binary(symbol, Box::new(left), Box::new(self.expr(level + 1)?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Symbol("-")) => Ok(match self.unary()? {
+                Expr::Number(value) => Expr::Number(value.wrapping_neg()),
+                value => Expr::Negate(Box::new(value)),
+            }),
+            Some(Token::Symbol("(")) => {
+                let value = self.expr(0)?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Name(name)) if !["if", "else", "while"].contains(&name.as_str()) => Ok(Expr::Variable(name)),
+            _ => {
+                self.position -= 1;
+                Err(self.error("expected a value"))
+            }
+        }
+    }
+}
+
+struct Compiler {
+    lines: Vec<String>,
+    variables: Vec<String>,
+    // Free registers, with the lowest numbered on top.
+    free: Vec<usize>,
+    labels: usize,
+}
+
+impl Compiler {
+    fn emit(&mut self, line: String) {
+        self.lines.push(line);
+    }
+
+    fn address(&mut self, name: &str) -> Result<usize, String> {
+        let index = match self.variables.iter().position(|variable| variable == name) {
+            Some(index) => index,
+            None => {
+                self.variables.push(name.to_string());
+                self.variables.len() - 1
+            }
+        };
+        if VARIABLE_BASE + index >= 256 {
+            return Err(format!("too many variables: at most {}", 256 - VARIABLE_BASE));
+        }
+        Ok(VARIABLE_BASE + index)
+    }
+
+    fn allocate(&mut self) -> Result<usize, String> {
+        self.free
+            .pop()
+            .ok_or_else(|| format!("expression needs more than {} registers", REGISTERS))
+    }
+
+    // Evaluates into a newly allocated register, which the caller frees.
+    fn expr(&mut self, expr: &Expr) -> Result<usize, String> {
+        match expr {
+            Expr::Number(value) => {
+                let register = self.allocate()?;
+                self.emit(format!("MOV R{}, #{}", register, value));
+                Ok(register)
+            }
+            Expr::Variable(name) => {
+                let address = self.address(name)?;
+                let register = self.allocate()?;
+                self.emit(format!("LDR R{}, {}", register, address));
+                Ok(register)
+            }
+            // Two's complement: invert, then add one.
+            Expr::Negate(value) => {
+                let register = self.expr(value)?;
+                self.emit(format!("MVN R{}, R{}", register, register));
+                self.emit(format!("ADD R{}, R{}, #1", register, register));
+                Ok(register)
+            }
+            Expr::Binary(symbol, left, right) => {
+                let mnemonic = match *symbol {
+                    "+" => "ADD",
+                    "-" => "SUB",
+                    "&" => "AND",
+                    "|" => "ORR",
+                    "^" => "EOR",
+                    "<<" => "LSL",
+                    _ => "LSR",
+                };
+                let register = self.expr(left)?;
+                let operand = self.operand(right)?;
+                self.emit(format!("{} R{}, R{}, {}", mnemonic, register, register, operand));
+                Ok(register)
+            }
+        }
+    }
+
+    // Numbers become immediates, saving a register.
+    fn operand(&mut self, expr: &Expr) -> Result<String, String> {
+        if let Expr::Number(value) = expr {
+            return Ok(format!("#{}", value));
+        }
+        let register = self.expr(expr)?;
+        self.free.push(register);
+        Ok(format!("R{}", register))
+    }
+
+    // Branches to `target` when the condition is false.
+    fn branch_unless(&mut self, condition: &Condition, target: &str) -> Result<(), String> {
+        let register = self.expr(&condition.left)?;
+        let operand = self.operand(&condition.right)?;
+        self.free.push(register);
+        self.emit(format!("CMP R{}, {}", register, operand));
+        let branches: &[&str] = match condition.relation {
+            "==" => &["BNE"],
+            "!=" => &["BEQ"],
+            "<" => &["BGT", "BEQ"],
+            ">" => &["BLT", "BEQ"],
+            "<=" => &["BGT"],
+            _ => &["BLT"],
+        };
+        for branch in branches {
+            self.emit(format!("{} {}", branch, target));
+        }
+        Ok(())
+    }
+
+    fn statements(&mut self, statements: &[Statement]) -> Result<(), String> {
+        for statement in statements {
+            match statement {
+                Statement::Assign(name, value) => {
+                    let register = self.expr(value)?;
+                    self.free.push(register);
+                    let address = self.address(name)?;
+                    self.emit(format!("STR R{}, {}", register, address));
+                }
+                Statement::If(condition, then, otherwise) => {
+                    let label = self.labels;
+                    self.labels += 1;
+                    if otherwise.is_empty() {
+                        self.branch_unless(condition, &format!("endif_{}", label))?;
+                        self.statements(then)?;
+                    } else {
+                        self.branch_unless(condition, &format!("else_{}", label))?;
+                        self.statements(then)?;
+                        self.emit(format!("B endif_{}", label));
+                        self.emit(format!("else_{}:", label));
+                        self.statements(otherwise)?;
+                    }
+                    self.emit(format!("endif_{}:", label));
+                }
+                Statement::While(condition, body) => {
+                    let label = self.labels;
+                    self.labels += 1;
+                    self.emit(format!("while_{}:", label));
+                    self.branch_unless(condition, &format!("endwhile_{}", label))?;
+                    self.statements(body)?;
+                    self.emit(format!("B while_{}", label));
+                    self.emit(format!("endwhile_{}:", label));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Compiles the tiny language to formatted assembly. The listing starts with a
+// comment giving the address of each variable.
+pub fn compile(source: &str) -> Result<String, String> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+    };
+    let mut program = vec![];
+    while parser.peek().is_some() {
+        program.push(parser.statement()?);
+    }
+
+    let mut compiler = Compiler {
+        lines: vec![],
+        variables: vec![],
+        free: (0..REGISTERS).rev().collect(),
+        labels: 0,
+    };
+    compiler.statements(&program)?;
+    compiler.emit(String::from("HALT"));
+
+    let mut assembly = String::new();
+    for (index, variable) in compiler.variables.iter().enumerate() {
+        assembly.push_str(&format!("; {} is at {}\n", variable, VARIABLE_BASE + index));
+    }
+    for line in &compiler.lines {
+        assembly.push_str(line);
+        assembly.push('\n');
+    }
+    Ok(format::format(&assembly))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Interpreter;
+
+    fn run(source: &str) -> Interpreter {
+        let mut int = Interpreter::new(compile(source).unwrap());
+        while int.running {
+            int.tick().unwrap();
+        }
+        int
+    }
+
+    #[test]
+    fn assignment_and_precedence() {
+        let int = run("x = 2 + 3 << 1;\ny = x | 1 & 2 ^ 4;\nz = -(x - 20);");
+        assert_eq!(int.mem[VARIABLE_BASE..VARIABLE_BASE + 3], [10, 14, 10]);
+    }
+
+    #[test]
+    fn listing() {
+        assert_eq!(
+            compile("a = 1;\nb = a + a;").unwrap(),
+            "; a is at 128\n; b is at 129\n    MOV   R0, #1\n    STR   R0, 128\n    LDR   R0, 128\n    LDR   R1, 128\n    ADD   R0, R0, R1\n    STR   R0, 129\n    HALT\n"
+        );
+    }
+
+    #[test]
+    fn control_flow() {
+        let source = "// sum 1 to 10, and the evens separately\n\
+                      i = 1;\nwhile i <= 10 {\n    total = total + i;\n    if i & 1 == 0 { evens = evens + i; } else { odds = odds + 1; }\n    i = i + 1;\n}\n\
+                      if total > 50 { big = 1; } else if total < 0 { big = -1; }";
+        let int = run(source);
+        assert_eq!(int.mem[VARIABLE_BASE..VARIABLE_BASE + 5], [11, 55, 30, 5, 1]);
+    }
+
+    #[test]
+    fn relations() {
+        for (relation, expected) in [("==", 0), ("!=", 1), ("<", 1), (">", 0), ("<=", 1), (">=", 0)] {
+            let int = run(&format!("a = 3;\nif a {} 4 {{ r = 1; }}", relation));
+            assert_eq!(int.mem[VARIABLE_BASE + 1], expected, "{}", relation);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(compile("x = 1 +;").err(), Some(String::from("source line 1: expected a value")));
+        assert_eq!(compile("x = 1;\nwhile x { }").err(), Some(String::from("source line 2: expected a comparison")));
+        assert_eq!(compile("x = 1").err(), Some(String::from("end of source: expected ';'")));
+        assert_eq!(compile("x = 1 $ 2;").err(), Some(String::from("source line 1: unexpected character '$'")));
+        let deep = format!("x = {}1{};", "1 + (".repeat(14), ")".repeat(14));
+        assert_eq!(compile(&deep).err(), Some(String::from("expression needs more than 13 registers")));
+    }
+}
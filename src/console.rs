@@ -0,0 +1,115 @@
+use std::io;
+use std::io::Write;
+
+use crate::Trap;
+
+// Where `INP` reads numbers from.
+pub enum Input {
+    Stdin,
+    Values(Vec<i32>),
+}
+
+// The input and output of the io extension. Output is always captured so
+// tests can check it, and echoed to stdout when running from the terminal.
+pub struct Console {
+    input: Input,
+    next: usize,
+    pub output: String,
+    pub echo: bool,
+}
+
+// Parses a list of numbers separated by commas or whitespace.
+pub fn parse_values(text: &str) -> Result<Vec<i32>, String> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|value| !value.is_empty())
+        .map(|value| value.parse().map_err(|_| format!("invalid input value '{}'", value)))
+        .collect()
+}
+
+// Echoing to a closed pipe isn't worth stopping the program for, since the
+// output is captured anyway.
+fn echo(text: &str) {
+    let mut stdout = io::stdout();
+    let _ = write!(stdout, "{}", text).and_then(|()| stdout.flush());
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Console {
+            input: Input::Values(vec![]),
+            next: 0,
+            output: String::new(),
+            echo: false,
+        }
+    }
+
+    pub fn set_input(&mut self, input: Input) {
+        self.input = input;
+        self.next = 0;
+    }
+
+    // Starts the input list again and clears the output. Lines already read
+    // from stdin can't be read again.
+    pub fn rewind(&mut self) {
+        self.next = 0;
+        self.output.clear();
+    }
+
+    pub fn read(&mut self) -> Result<i32, Trap> {
+        match &self.input {
+            Input::Values(values) => {
+                let value = values.get(self.next).copied().ok_or_else(|| Trap::Input(String::from("no input left")))?;
+                self.next += 1;
+                Ok(value)
+            }
+            Input::Stdin => {
+                if self.echo {
+                    echo("? ");
+                }
+                let mut line = String::new();
+                if io::stdin().read_line(&mut line).unwrap_or(0) == 0 {
+                    return Err(Trap::Input(String::from("no input left")));
+                }
+                line.trim()
+                    .parse()
+                    .map_err(|_| Trap::Input(format!("invalid input '{}'", line.trim())))
+            }
+        }
+    }
+
+    // Writes the character with the given code, as `OUTC` does.
+    pub fn write_char(&mut self, code: i32) {
+        let c = char::from_u32(code as u32).unwrap_or(char::REPLACEMENT_CHARACTER);
+        self.write(&c.to_string());
+    }
+
+    pub fn write(&mut self, text: &str) {
+        self.output.push_str(text);
+        if self.echo {
+            echo(text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values() {
+        assert_eq!(parse_values("1, -2 3\n4"), Ok(vec![1, -2, 3, 4]));
+        assert_eq!(parse_values(""), Ok(vec![]));
+        assert_eq!(parse_values("1,x"), Err(String::from("invalid input value 'x'")));
+    }
+
+    #[test]
+    fn reads_and_rewinds() {
+        let mut console = Console::new();
+        console.set_input(Input::Values(vec![7]));
+        assert_eq!(console.read(), Ok(7));
+        assert_eq!(console.read(), Err(Trap::Input(String::from("no input left"))));
+        console.write("7\n");
+        console.rewind();
+        assert_eq!((console.read(), console.output.as_str()), (Ok(7), ""));
+    }
+}
@@ -0,0 +1,211 @@
+use crate::Interpreter;
+use crate::instruction::code;
+
+const CONDITIONAL_BRANCHES: [&str; 4] = ["BEQ", "BNE", "BGT", "BLT"];
+
+fn is_instruction(line: &str) -> bool {
+    let code = code(line);
+    !code.is_empty() && !code.ends_with(':')
+}
+
+fn is_conditional_branch(line: &str) -> bool {
+    code(line)
+        .split_whitespace()
+        .next()
+        .is_some_and(|mnemonic| CONDITIONAL_BRANCHES.contains(&mnemonic))
+}
+
+fn percent(hit: usize, total: usize) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        100.0 * hit as f64 / total as f64
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+struct Summary {
+    lines_hit: usize,
+    lines: usize,
+    branches_hit: usize,
+    branches: usize,
+}
+
+// Each conditional branch counts as two branches: taken and not taken.
+fn summarise(int: &Interpreter) -> Summary {
+    let mut summary = Summary {
+        lines_hit: 0,
+        lines: 0,
+        branches_hit: 0,
+        branches: 0,
+    };
+    for (index, line) in int.source.iter().enumerate() {
+        if !is_instruction(line) {
+            continue;
+        }
+        summary.lines += 1;
+        if int.stats.line_hits[index] > 0 {
+            summary.lines_hit += 1;
+        }
+        if is_conditional_branch(line) {
+            let (taken, not_taken) = int.stats.branches[index];
+            summary.branches += 2;
+            summary.branches_hit += usize::from(taken > 0) + usize::from(not_taken > 0);
+        }
+    }
+    summary
+}
+
+fn summary_line(int: &Interpreter) -> String {
+    let summary = summarise(int);
+    format!(
+        "Lines: {}/{} ({:.1}%)  Branches: {}/{} ({:.1}%)",
+        summary.lines_hit,
+        summary.lines,
+        percent(summary.lines_hit, summary.lines),
+        summary.branches_hit,
+        summary.branches,
+        percent(summary.branches_hit, summary.branches)
+    )
+}
+
+// A gcov-style listing: execution count, line number, source. Lines that were
+// never executed are marked `#####`, and lines that aren't instructions `-`.
+pub fn annotated(int: &Interpreter) -> String {
+    let mut listing = String::new();
+    for (index, line) in int.source.iter().enumerate() {
+        let hits = int.stats.line_hits[index];
+        let count = if !is_instruction(line) {
+            String::from("-")
+        } else if hits == 0 {
+            String::from("#####")
+        } else {
+            hits.to_string()
+        };
+        listing.push_str(&format!("{:>8} | {:>4} | {}", count, index, line));
+        if is_conditional_branch(line) {
+            let (taken, not_taken) = int.stats.branches[index];
+            listing.push_str(&format!("    [taken {}, not taken {}]", taken, not_taken));
+        }
+        listing.push('\n');
+    }
+    listing.push_str(&summary_line(int));
+    listing.push('\n');
+    listing
+}
+
+// LCOV tracefile. LCOV line numbers are 1-based, unlike `line_num`.
+pub fn lcov(int: &Interpreter, source_path: &str) -> String {
+    let summary = summarise(int);
+    let mut trace = format!("TN:\nSF:{}\n", source_path);
+    for (index, line) in int.source.iter().enumerate() {
+        if !is_conditional_branch(line) {
+            continue;
+        }
+        let (taken, not_taken) = int.stats.branches[index];
+        let count = |count: u64| {
+            if int.stats.line_hits[index] == 0 {
+                String::from("-")
+            } else {
+                count.to_string()
+            }
+        };
+        trace.push_str(&format!("BRDA:{},0,0,{}\n", index + 1, count(taken)));
+        trace.push_str(&format!("BRDA:{},0,1,{}\n", index + 1, count(not_taken)));
+    }
+    trace.push_str(&format!("BRF:{}\nBRH:{}\n", summary.branches, summary.branches_hit));
+    for (index, line) in int.source.iter().enumerate() {
+        if is_instruction(line) {
+            trace.push_str(&format!("DA:{},{}\n", index + 1, int.stats.line_hits[index]));
+        }
+    }
+    trace.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", summary.lines, summary.lines_hit));
+    trace
+}
+
+pub fn html(int: &Interpreter, title: &str) -> String {
+    let mut page = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    page.push_str(&format!("<title>Coverage: {}</title>\n", html_escape(title)));
+    page.push_str(
+        "<style>\n\
+         body { font-family: sans-serif; }\n\
+         table { border-collapse: collapse; font-family: monospace; }\n\
+         td { padding: 0 0.75em; white-space: pre; }\n\
+         .count, .line { text-align: right; color: #555; }\n\
+         .hit { background: #dfd; }\n\
+         .miss { background: #fcc; }\n\
+         .partial { background: #ffc; }\n\
+         </style>\n</head>\n<body>\n",
+    );
+    page.push_str(&format!("<h1>{}</h1>\n<p>{}</p>\n<table>\n", html_escape(title), summary_line(int)));
+
+    for (index, line) in int.source.iter().enumerate() {
+        let hits = int.stats.line_hits[index];
+        let mut note = String::new();
+        let class = if !is_instruction(line) {
+            ""
+        } else if hits == 0 {
+            "miss"
+        } else if is_conditional_branch(line) {
+            let (taken, not_taken) = int.stats.branches[index];
+            note = format!("taken {}, not taken {}", taken, not_taken);
+            if taken > 0 && not_taken > 0 { "hit" } else { "partial" }
+        } else {
+            "hit"
+        };
+        let count = if is_instruction(line) { hits.to_string() } else { String::new() };
+        page.push_str(&format!(
+            "<tr class=\"{}\"><td class=\"count\">{}</td><td class=\"line\">{}</td><td>{}</td><td>{}</td></tr>\n",
+            class,
+            count,
+            index,
+            html_escape(line),
+            note
+        ));
+    }
+    page.push_str("</table>\n</body>\n</html>\n");
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Extensions;
+    use crate::testcase;
+
+    fn covered() -> Interpreter {
+        let source = "LDR R0, 40\nCMP R0, #0\nBEQ zero\nMOV R1, #1\nHALT\nzero:\nMOV R1, #2\nHALT";
+        let mut int = Interpreter::new(source.to_string());
+        let cases = testcase::parse("case positive\nset 40 5\nexpect R1 1", Extensions::default()).unwrap();
+        testcase::run_case(&mut int, &cases[0]);
+        int
+    }
+
+    #[test]
+    fn annotated_listing() {
+        let listing = annotated(&covered());
+        assert!(listing.contains("       1 |    2 | BEQ zero    [taken 0, not taken 1]\n"));
+        assert!(listing.contains("       - |    5 | zero:\n"));
+        assert!(listing.contains("   ##### |    6 | MOV R1, #2\n"));
+        assert!(listing.ends_with("Lines: 5/7 (71.4%)  Branches: 1/2 (50.0%)\n"));
+    }
+
+    #[test]
+    fn lcov_trace() {
+        let trace = lcov(&covered(), "prog.asm");
+        assert!(trace.starts_with("TN:\nSF:prog.asm\nBRDA:3,0,0,0\nBRDA:3,0,1,1\nBRF:2\nBRH:1\nDA:1,1\n"));
+        assert!(trace.contains("DA:7,0\n"));
+        assert!(!trace.contains("DA:6,"));
+        assert!(trace.ends_with("LF:7\nLH:5\nend_of_record\n"));
+    }
+
+    #[test]
+    fn html_report() {
+        let page = html(&covered(), "prog.asm");
+        assert!(page.contains("<tr class=\"partial\"><td class=\"count\">1</td><td class=\"line\">2</td><td>BEQ zero</td>"));
+        assert!(page.contains("<tr class=\"miss\">"));
+    }
+}
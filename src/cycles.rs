@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+
+// Memory accesses go out over the bus, so they cost more than register
+// operations; branches pay for refilling the fetch. PUSH and POP reach memory
+// like STR and LDR, and BL and RET are branches.
+const DEFAULT_COSTS: [(&str, u64); 25] = [
+    ("LDR", 4),
+    ("STR", 4),
+    ("ADD", 1),
+    ("SUB", 1),
+    ("MOV", 1),
+    ("CMP", 1),
+    ("B", 2),
+    ("BEQ", 2),
+    ("BNE", 2),
+    ("BGT", 2),
+    ("BLT", 2),
+    ("AND", 1),
+    ("ORR", 1),
+    ("EOR", 1),
+    ("MVN", 1),
+    ("LSL", 1),
+    ("LSR", 1),
+    ("HALT", 1),
+    ("INP", 1),
+    ("OUT", 1),
+    ("OUTC", 1),
+    ("BL", 2),
+    ("RET", 2),
+    ("PUSH", 4),
+    ("POP", 4),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CostModel {
+    costs: HashMap<String, u64>,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self {
+            costs: DEFAULT_COSTS
+                .iter()
+                .map(|(mnemonic, cost)| (mnemonic.to_string(), *cost))
+                .collect(),
+        }
+    }
+}
+
+impl CostModel {
+    // Reads `MNEMONIC = cycles` lines, overriding the defaults for just the
+    // mnemonics listed.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut model = Self::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: String| format!("cost line {}: {}", index + 1, message);
+            let (mnemonic, cost) = line
+                .split_once('=')
+                .ok_or_else(|| error(String::from("expected 'MNEMONIC = cycles'")))?;
+            let (mnemonic, cost) = (mnemonic.trim().to_uppercase(), cost.trim());
+            if !model.costs.contains_key(&mnemonic) {
+                return Err(error(format!("unknown instruction '{}'", mnemonic)));
+            }
+            let cost = cost
+                .parse::<u64>()
+                .map_err(|_| error(format!("invalid cycle count '{}'", cost)))?;
+            model.costs.insert(mnemonic, cost);
+        }
+        Ok(model)
+    }
+
+    pub fn cost(&self, mnemonic: &str) -> u64 {
+        self.costs.get(mnemonic).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let model = CostModel::default();
+        assert_eq!(model.cost("LDR"), 4);
+        assert_eq!(model.cost("ADD"), 1);
+        assert_eq!(model.cost("BEQ"), 2);
+        assert_eq!((model.cost("PUSH"), model.cost("POP"), model.cost("RET"), model.cost("OUTC")), (4, 4, 2, 1));
+        assert_eq!(model.cost("JMP"), 0);
+    }
+
+    #[test]
+    fn overrides() {
+        let model = CostModel::parse("# slow memory\nldr = 10\nSTR=12\n").unwrap();
+        assert_eq!(model.cost("LDR"), 10);
+        assert_eq!(model.cost("STR"), 12);
+        assert_eq!(model.cost("MOV"), 1);
+        assert_eq!(CostModel::parse("push = 6\nBL = 3").unwrap().cost("PUSH"), 6);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(CostModel::parse("JMP = 1"), Err(String::from("cost line 1: unknown instruction 'JMP'")));
+        assert_eq!(CostModel::parse("\nADD 1"), Err(String::from("cost line 2: expected 'MNEMONIC = cycles'")));
+        assert_eq!(CostModel::parse("ADD = -1"), Err(String::from("cost line 1: invalid cycle count '-1'")));
+    }
+}
@@ -0,0 +1,537 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::iter;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::Interpreter;
+use crate::instruction::Extensions;
+use crate::instruction::code;
+use crate::json::Json;
+use crate::json::object;
+use crate::lsp::read_message;
+use crate::lsp::write_message;
+
+// Instructions run between checks for a pause request while continuing.
+const CHUNK: usize = 1000;
+
+// There is one thread and one stack frame. Variable references name the scopes.
+const THREAD: i32 = 1;
+const FRAME: i32 = 1;
+const REGISTERS: i64 = 1;
+const MEMORY: i64 = 2;
+const COMPARISON: i64 = 3;
+
+// The label a call went to, read from the `BL` just before its return address.
+fn callee(int: &Interpreter, address: usize) -> String {
+    address
+        .checked_sub(1)
+        .and_then(|address| int.source_line(address))
+        .and_then(|line| code(&int.source[line]).split_whitespace().nth(1).map(str::to_string))
+        .unwrap_or_else(|| String::from("subroutine"))
+}
+
+pub struct Adapter {
+    int: Option<Interpreter>,
+    program: String,
+    // 0-based source lines, like `line_num`.
+    breakpoints: HashSet<usize>,
+    stop_on_entry: bool,
+    // Whether the program is running freely rather than stopped.
+    continuing: bool,
+    // While stepping out, the number of running calls to stop below.
+    step_out: Option<usize>,
+    disconnected: bool,
+    seq: i64,
+}
+
+impl Adapter {
+    pub fn new() -> Self {
+        Adapter {
+            int: None,
+            program: String::new(),
+            breakpoints: HashSet::new(),
+            stop_on_entry: false,
+            continuing: false,
+            step_out: None,
+            disconnected: false,
+            seq: 0,
+        }
+    }
+
+    // Numbers messages in the order they are sent, whatever order they were built in.
+    fn numbered(&mut self, mut messages: Vec<Json>) -> Vec<Json> {
+        for message in &mut messages {
+            if let Json::Object(pairs) = message {
+                self.seq += 1;
+                pairs.insert(0, (String::from("seq"), Json::from(self.seq)));
+            }
+        }
+        messages
+    }
+
+    fn event(&self, event: &str, body: Json) -> Json {
+        object([
+            ("type", Json::from("event")),
+            ("event", Json::from(event)),
+            ("body", body),
+        ])
+    }
+
+    fn response(&self, request: &Json, result: Result<Json, String>) -> Json {
+        let (success, body, message) = match result {
+            Ok(body) => (true, body, Json::Null),
+            Err(message) => (false, object([]), Json::from(message)),
+        };
+        object([
+            ("type", Json::from("response")),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", Json::from(success)),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+            ("message", message),
+            ("body", body),
+        ])
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> Json {
+        self.continuing = false;
+        self.step_out = None;
+        let body = object([
+            ("reason", Json::from(reason)),
+            ("threadId", Json::from(THREAD)),
+            ("text", Json::from(text)),
+            ("allThreadsStopped", Json::from(true)),
+        ]);
+        self.event("stopped", body)
+    }
+
+    fn current_line(&self) -> Option<usize> {
+        let int = self.int.as_ref()?;
+        int.source_line(int.line_num)
+    }
+
+    // Runs one instruction and reports anything that stops the program.
+    // Without a launched program there is nothing to run, so the session ends.
+    fn tick(&mut self) -> Option<Vec<Json>> {
+        let Some(int) = self.int.as_mut() else {
+            self.continuing = false;
+            return Some(vec![self.event("terminated", object([]))]);
+        };
+        if !int.running {
+            self.continuing = false;
+            return Some(vec![]);
+        }
+        if let Err(trap) = int.tick() {
+            let text = format!("Trap at line {}: {}", int.line_num, trap);
+            let output = object([("category", Json::from("stderr")), ("output", Json::from(format!("{}\n", text)))]);
+            let output = self.event("output", output);
+            return Some(vec![output, self.stopped("exception", Some(text))]);
+        }
+        if !int.running {
+            self.continuing = false;
+            let exited = self.event("exited", object([("exitCode", Json::from(0))]));
+            return Some(vec![exited, self.event("terminated", object([]))]);
+        }
+        None
+    }
+
+    fn step(&mut self) -> Vec<Json> {
+        self.tick().unwrap_or_else(|| vec![self.stopped("step", None)])
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        self.current_line().is_some_and(|line| self.breakpoints.contains(&line))
+    }
+
+    // Runs a batch of instructions while continuing, stopping at breakpoints
+    // and, when stepping out, once the current call has returned.
+    pub fn run_chunk(&mut self) -> Vec<Json> {
+        for _ in 0..CHUNK {
+            if let Some(events) = self.tick() {
+                return self.numbered(events);
+            }
+            if let (Some(depth), Some(int)) = (self.step_out, &self.int)
+                && int.calls.len() < depth
+            {
+                let stopped = self.stopped("step", None);
+                return self.numbered(vec![stopped]);
+            }
+            if self.at_breakpoint() {
+                let stopped = self.stopped("breakpoint", None);
+                return self.numbered(vec![stopped]);
+            }
+        }
+        vec![]
+    }
+
+    fn launch(&mut self, arguments: &Json) -> Result<Json, String> {
+        let program = arguments.get("program").and_then(Json::as_str).ok_or("missing 'program'")?;
+        let source = fs::read_to_string(program).map_err(|error| format!("{}: {}", program, error))?;
+        let mut int = Interpreter::new(source);
+        if let Some(names) = arguments.get("dialect").and_then(Json::as_str) {
+            int.extensions = Extensions::parse(names)?;
+            int.reset();
+        }
+        if arguments.get("vonNeumann").and_then(Json::as_bool) == Some(true) {
+            int.load_into_memory().map_err(|(line, trap)| format!("line {}: {}", line, trap))?;
+        }
+        self.int = Some(int);
+        self.program = program.to_string();
+        self.stop_on_entry = arguments.get("stopOnEntry").and_then(Json::as_bool) == Some(true);
+        Ok(object([]))
+    }
+
+    // DAP lines count from 1 unless the client says otherwise; ours count from 0.
+    fn set_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let lines: Vec<usize> = arguments
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or(&[])
+            .iter()
+            .filter_map(|breakpoint| breakpoint.get("line").and_then(Json::as_i64))
+            .map(|line| line.max(1) as usize - 1)
+            .collect();
+        self.breakpoints = lines.iter().copied().collect();
+        let breakpoints = lines
+            .iter()
+            .map(|line| {
+                let verified = self.int.as_ref().is_none_or(|int| line < &int.source.len());
+                object([("verified", Json::from(verified)), ("line", Json::from(line + 1))])
+            })
+            .collect::<Vec<Json>>();
+        Ok(object([("breakpoints", Json::from(breakpoints))]))
+    }
+
+    fn stack_trace(&self) -> Result<Json, String> {
+        let int = self.int.as_ref().ok_or("no program launched")?;
+        let line = self.current_line().unwrap_or(int.source.len());
+        let frame = |id: i32, name: &str, line: usize| {
+            object([
+                ("id", Json::from(id)),
+                ("name", Json::from(name)),
+                ("line", Json::from(line + 1)),
+                ("column", Json::from(1)),
+                ("source", object([("path", Json::from(self.program.as_str()))])),
+            ])
+        };
+        // Each running call adds a frame at the line it returns to, and each
+        // frame is named after the subroutine it is in, so the outermost is
+        // `main`. Every frame shares the one set of registers and memory.
+        let lines = iter::once(line).chain(int.call_stack());
+        let names = int.calls.iter().rev().map(|&address| callee(int, address)).chain(iter::once(String::from("main")));
+        let frames: Vec<Json> = lines
+            .zip(names)
+            .enumerate()
+            .map(|(depth, (line, name))| frame(FRAME + depth as i32, &name, line))
+            .collect();
+        let total = frames.len();
+        Ok(object([("stackFrames", Json::from(frames)), ("totalFrames", Json::from(total))]))
+    }
+
+    fn scopes() -> Json {
+        let scope = |name: &str, reference: i64| {
+            object([
+                ("name", Json::from(name)),
+                ("variablesReference", Json::from(reference)),
+                ("expensive", Json::from(false)),
+            ])
+        };
+        let scopes = vec![scope("Registers", REGISTERS), scope("Memory", MEMORY), scope("Comparison", COMPARISON)];
+        object([("scopes", Json::from(scopes))])
+    }
+
+    fn variables(&self, arguments: &Json) -> Result<Json, String> {
+        let int = self.int.as_ref().ok_or("no program launched")?;
+        let variable = |name: String, value: String| {
+            object([
+                ("name", Json::from(name)),
+                ("value", Json::from(value)),
+                ("variablesReference", Json::from(0)),
+            ])
+        };
+        let variables: Vec<Json> = match arguments.get("variablesReference").and_then(Json::as_i64) {
+            Some(REGISTERS) => {
+                let mut registers: Vec<Json> = (0..16)
+                    .map(|register| variable(format!("R{}", register), int.reg[register].to_string()))
+                    .collect();
+                registers.push(variable(String::from("PC"), int.line_num.to_string()));
+                registers
+            }
+            Some(MEMORY) => (0..int.mem.len())
+                .map(|address| variable(format!("mem[{}]", address), int.mem[address].to_string()))
+                .collect(),
+            Some(COMPARISON) => vec![variable(String::from("cmp"), format!("{:?}", int.cmp))],
+            _ => vec![],
+        };
+        Ok(object([("variables", Json::from(variables))]))
+    }
+
+    // Handles one request and returns the messages to send back.
+    pub fn handle(&mut self, request: &Json) -> Vec<Json> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let arguments = request.get("arguments").cloned().unwrap_or(Json::Null);
+        let mut after = vec![];
+        let result = match command {
+            "initialize" => {
+                after.push(self.event("initialized", object([])));
+                Ok(object([("supportsConfigurationDoneRequest", Json::from(true))]))
+            }
+            "launch" => self.launch(&arguments),
+            "setBreakpoints" => self.set_breakpoints(&arguments),
+            "setExceptionBreakpoints" => Ok(object([("breakpoints", Json::from(vec![]))])),
+            "configurationDone" => {
+                // Breakpoints are only checked after each instruction, so one
+                // on the first instruction is checked here.
+                if self.stop_on_entry {
+                    after.push(self.stopped("entry", None));
+                } else if self.at_breakpoint() {
+                    after.push(self.stopped("breakpoint", None));
+                } else {
+                    self.continuing = true;
+                }
+                Ok(object([]))
+            }
+            "threads" => {
+                let thread = object([("id", Json::from(THREAD)), ("name", Json::from("main"))]);
+                Ok(object([("threads", Json::from(vec![thread]))]))
+            }
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(Self::scopes()),
+            "variables" => self.variables(&arguments),
+            "continue" => {
+                self.continuing = true;
+                self.step_out = None;
+                Ok(object([("allThreadsContinued", Json::from(true))]))
+            }
+            // One instruction at a time, so stepping in follows `BL` like `next` does.
+            "next" | "stepIn" => {
+                after = self.step();
+                Ok(object([]))
+            }
+            // Runs until the innermost call returns. Outside any call that is
+            // the end of the program, as with `continue`.
+            "stepOut" => {
+                self.step_out = self.int.as_ref().map(|int| int.calls.len());
+                self.continuing = true;
+                Ok(object([]))
+            }
+            "pause" => {
+                after.push(self.stopped("pause", None));
+                Ok(object([]))
+            }
+            "disconnect" => {
+                self.disconnected = true;
+                Ok(object([]))
+            }
+            _ => Err(format!("unsupported command '{}'", command)),
+        };
+        let mut replies = vec![self.response(request, result)];
+        replies.extend(after);
+        self.numbered(replies)
+    }
+}
+
+// Serves one client. Requests are read on a separate thread so that `pause`
+// can arrive while the program is running.
+pub fn run<R: BufRead + Send + 'static, W: Write>(mut input: R, output: &mut W) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        while let Ok(Some(body)) = read_message(&mut input) {
+            if sender.send(body).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut adapter = Adapter::new();
+    loop {
+        let body = if adapter.continuing {
+            match receiver.try_recv() {
+                Ok(body) => Some(body),
+                Err(mpsc::TryRecvError::Empty) => None,
+                Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+            }
+        } else {
+            match receiver.recv() {
+                Ok(body) => Some(body),
+                Err(_) => return Ok(()),
+            }
+        };
+        let messages = match body {
+            Some(body) => {
+                let Ok(request) = Json::parse(&body) else {
+                    continue;
+                };
+                adapter.handle(&request)
+            }
+            None => adapter.run_chunk(),
+        };
+        for message in messages {
+            write_message(output, &message)?;
+        }
+        if adapter.disconnected {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(adapter: &mut Adapter, command: &str, arguments: Json) -> Vec<Json> {
+        let request = object([
+            ("seq", Json::from(1)),
+            ("type", Json::from("request")),
+            ("command", Json::from(command)),
+            ("arguments", arguments),
+        ]);
+        let replies = adapter.handle(&request);
+        assert_eq!(replies[0].get("success"), Some(&Json::from(true)), "{}", replies[0]);
+        replies
+    }
+
+    fn events(replies: &[Json]) -> Vec<&str> {
+        replies.iter().filter_map(|reply| reply.get("event").and_then(Json::as_str)).collect()
+    }
+
+    fn launched(source: &str, name: &str, stop_on_entry: bool) -> Adapter {
+        let path = std::env::temp_dir().join(format!("ian-dap-{}-{}.asm", std::process::id(), name));
+        fs::write(&path, source).unwrap();
+        let mut adapter = Adapter::new();
+        assert_eq!(events(&request(&mut adapter, "initialize", object([]))), vec!["initialized"]);
+        let arguments = object([
+            ("program", Json::from(path.to_str().unwrap())),
+            ("stopOnEntry", Json::from(stop_on_entry)),
+        ]);
+        request(&mut adapter, "launch", arguments);
+        fs::remove_file(path).unwrap();
+        adapter
+    }
+
+    fn variable(adapter: &mut Adapter, reference: i64, index: usize) -> Json {
+        let replies = request(adapter, "variables", object([("variablesReference", Json::from(reference))]));
+        replies[0].at(&["body", "variables"]).and_then(Json::as_array).unwrap()[index].clone()
+    }
+
+    #[test]
+    fn steps_and_inspects() {
+        let mut adapter = launched("MOV R0, #5\nCMP R0, #5\nSTR R0, 3\nHALT", "step", true);
+        assert_eq!(events(&request(&mut adapter, "configurationDone", object([]))), vec!["stopped"]);
+        assert_eq!(events(&request(&mut adapter, "next", object([]))), vec!["stopped"]);
+        let replies = request(&mut adapter, "stackTrace", object([]));
+        assert_eq!(replies[0].at(&["body", "stackFrames"]).and_then(Json::as_array).unwrap()[0].get("line"), Some(&Json::from(2)));
+
+        request(&mut adapter, "next", object([]));
+        request(&mut adapter, "next", object([]));
+        assert_eq!(variable(&mut adapter, REGISTERS, 0).get("value"), Some(&Json::from("5")));
+        assert_eq!(variable(&mut adapter, MEMORY, 3).get("name"), Some(&Json::from("mem[3]")));
+        assert_eq!(variable(&mut adapter, MEMORY, 3).get("value"), Some(&Json::from("5")));
+        assert_eq!(variable(&mut adapter, COMPARISON, 0).get("value"), Some(&Json::from("EQ")));
+        assert_eq!(events(&request(&mut adapter, "next", object([]))), vec!["exited", "terminated"]);
+    }
+
+    #[test]
+    fn call_stack() {
+        let source = "BL outer\nHALT\nouter:\nPUSH R14\nBL inner\nPOP R14\nRET\ninner:\nRET";
+        let mut adapter = launched(source, "calls", true);
+        let int = adapter.int.as_mut().unwrap();
+        int.extensions.stack = true;
+        int.reset();
+        request(&mut adapter, "configurationDone", object([]));
+        for _ in 0..3 {
+            request(&mut adapter, "next", object([]));
+        }
+        let replies = request(&mut adapter, "stackTrace", object([]));
+        let frames = replies[0].at(&["body", "stackFrames"]).and_then(Json::as_array).unwrap();
+        let lines: Vec<_> = frames.iter().map(|frame| frame.get("line").and_then(Json::as_i64).unwrap()).collect();
+        assert_eq!(lines, [9, 6, 2]);
+        let names: Vec<_> = frames.iter().map(|frame| frame.get("name").and_then(Json::as_str).unwrap()).collect();
+        assert_eq!(names, ["inner", "outer", "main"]);
+
+        let mut adapter = launched("MOV R0, #1\nHALT", "no-calls", true);
+        request(&mut adapter, "configurationDone", object([]));
+        let replies = request(&mut adapter, "stackTrace", object([]));
+        let frames = replies[0].at(&["body", "stackFrames"]).and_then(Json::as_array).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].get("name"), Some(&Json::from("main")));
+    }
+
+    #[test]
+    fn step_out() {
+        let mut adapter = launched("BL sub\nHALT\nsub:\nMOV R0, #1\nMOV R1, #2\nRET", "step-out", true);
+        adapter.int.as_mut().unwrap().extensions.stack = true;
+        request(&mut adapter, "configurationDone", object([]));
+        request(&mut adapter, "stepIn", object([]));
+        request(&mut adapter, "stepOut", object([]));
+        assert!(adapter.continuing);
+        let replies = adapter.run_chunk();
+        assert_eq!(replies[0].at(&["body", "reason"]), Some(&Json::from("step")));
+        assert_eq!(adapter.current_line(), Some(1));
+        assert_eq!(variable(&mut adapter, REGISTERS, 1).get("value"), Some(&Json::from("2")));
+    }
+
+    #[test]
+    fn breakpoint_on_first_line() {
+        let mut adapter = launched("MOV R0, #1\nHALT", "first-line", false);
+        let breakpoints = object([("breakpoints", Json::from(vec![object([("line", Json::from(1))])]))]);
+        request(&mut adapter, "setBreakpoints", breakpoints);
+        let replies = request(&mut adapter, "configurationDone", object([]));
+        assert_eq!(replies[1].at(&["body", "reason"]), Some(&Json::from("breakpoint")));
+        assert!(!adapter.continuing);
+        // Continuing from the breakpoint doesn't stop on it again.
+        request(&mut adapter, "continue", object([]));
+        assert_eq!(events(&adapter.run_chunk()), vec!["exited", "terminated"]);
+    }
+
+    #[test]
+    fn breakpoints_and_pause() {
+        let mut adapter = launched("MOV R0, #0\nloop:\nADD R0, R0, #1\nB loop", "run", false);
+        let breakpoints = object([("breakpoints", Json::from(vec![object([("line", Json::from(3))])]))]);
+        let replies = request(&mut adapter, "setBreakpoints", breakpoints);
+        assert_eq!(replies[0].at(&["body", "breakpoints"]), Some(&Json::from(vec![object([("verified", Json::from(true)), ("line", Json::from(3))])])));
+
+        request(&mut adapter, "configurationDone", object([]));
+        assert!(adapter.continuing);
+        assert_eq!(events(&adapter.run_chunk()), vec!["stopped"]);
+        request(&mut adapter, "continue", object([]));
+        adapter.run_chunk();
+        assert_eq!(variable(&mut adapter, REGISTERS, 0).get("value"), Some(&Json::from("1")));
+
+        request(&mut adapter, "setBreakpoints", object([("breakpoints", Json::from(vec![]))]));
+        request(&mut adapter, "continue", object([]));
+        assert_eq!(adapter.run_chunk(), vec![]);
+        let replies = request(&mut adapter, "pause", object([]));
+        assert_eq!(replies[1].at(&["body", "reason"]), Some(&Json::from("pause")));
+        let seq = |reply: &Json| reply.get("seq").and_then(Json::as_i64).unwrap();
+        assert_eq!(seq(&replies[1]), seq(&replies[0]) + 1);
+        assert!(!adapter.continuing);
+    }
+
+    #[test]
+    fn without_a_program() {
+        let mut adapter = Adapter::new();
+        request(&mut adapter, "configurationDone", object([]));
+        assert!(adapter.continuing);
+        assert_eq!(events(&adapter.run_chunk()), vec!["terminated"]);
+        assert!(!adapter.continuing);
+        assert_eq!(events(&request(&mut adapter, "next", object([]))), vec!["terminated"]);
+    }
+
+    #[test]
+    fn traps_and_errors() {
+        let mut adapter = launched("MOV R0, 1", "trap", true);
+        request(&mut adapter, "configurationDone", object([]));
+        let replies = request(&mut adapter, "next", object([]));
+        assert_eq!(events(&replies), vec!["output", "stopped"]);
+        assert_eq!(replies[2].at(&["body", "reason"]), Some(&Json::from("exception")));
+
+        let replies = adapter.handle(&object([("command", Json::from("evaluate"))]));
+        assert_eq!(replies[0].get("success"), Some(&Json::from(false)));
+        request(&mut adapter, "disconnect", object([]));
+        assert!(adapter.disconnected);
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use crate::Interpreter;
+use crate::Trap;
+use crate::flow::Flow;
+use crate::instruction::Condition;
+use crate::instruction::Instruction;
+use crate::instruction::MemoryRef;
+use crate::instruction::Operand;
+use crate::instruction::label;
+
+const INDENT: &str = "    ";
+
+#[derive(Clone, Copy)]
+enum Item<'a> {
+    Label(&'a str),
+    Code(Instruction),
+}
+
+enum Statement {
+    Line(String),
+    Label(String),
+    If(String, Vec<Statement>, Vec<Statement>),
+    While(String, Vec<Statement>),
+    Repeat(Vec<Statement>, String),
+}
+
+fn operand(operand: Operand) -> String {
+    match operand {
+        Operand::Immediate(value) => value.to_string(),
+        Operand::Register(register) => format!("R{}", register),
+    }
+}
+
+// Direct addresses are variables such as `mem40`; indexed ones are written
+// as an array access such as `mem[R1 + 4]`.
+fn memory(memory: MemoryRef) -> String {
+    match memory {
+        MemoryRef::Direct(address) => format!("mem{}", address),
+        MemoryRef::Indexed(n, 0) => format!("mem[R{}]", n),
+        MemoryRef::Indexed(n, offset) if offset < 0 => format!("mem[R{} - {}]", n, offset.unsigned_abs()),
+        MemoryRef::Indexed(n, offset) => format!("mem[R{} + {}]", n, offset),
+    }
+}
+
+fn relation(condition: Condition, negated: bool) -> &'static str {
+    match (condition, negated) {
+        (Condition::EQ, false) | (Condition::NE, true) => "=",
+        (Condition::NE, false) | (Condition::EQ, true) => "≠",
+        (Condition::GT, false) => ">",
+        (Condition::GT, true) => "≤",
+        (Condition::LT, false) => "<",
+        (Condition::LT, true) => "≥",
+        (Condition::Always, _) => unreachable!("unconditional branches have no relation"),
+    }
+}
+
+fn assignment(instruction: Instruction) -> String {
+    let binary = |d: usize, n: usize, symbol: &str, op: Operand| format!("R{} ← R{} {} {}", d, n, symbol, operand(op));
+    match instruction {
+        Instruction::Ldr(d, address) => format!("R{} ← {}", d, memory(address)),
+        Instruction::Str(d, address) => format!("{} ← R{}", memory(address), d),
+        Instruction::Add(d, n, op) => binary(d, n, "+", op),
+        Instruction::Sub(d, n, op) => binary(d, n, "-", op),
+        Instruction::And(d, n, op) => binary(d, n, "AND", op),
+        Instruction::Orr(d, n, op) => binary(d, n, "OR", op),
+        Instruction::Eor(d, n, op) => binary(d, n, "XOR", op),
+        Instruction::Lsl(d, n, op) => binary(d, n, "<<", op),
+        Instruction::Lsr(d, n, op) => binary(d, n, ">>", op),
+        Instruction::Mov(d, op) => format!("R{} ← {}", d, operand(op)),
+        Instruction::Mvn(d, op) => format!("R{} ← NOT {}", d, operand(op)),
+        Instruction::Cmp(n, op) => format!("# compare R{} with {}", n, operand(op)),
+        Instruction::Halt => String::from("STOP"),
+        Instruction::Inp(d) => format!("R{} ← USERINPUT", d),
+        Instruction::Out(d) => format!("OUTPUT R{}", d),
+        Instruction::Outc(d) => format!("OUTPUT CHR(R{})", d),
+        Instruction::Ret => String::from("RETURN"),
+        Instruction::Push(d) => format!("PUSH R{}", d),
+        Instruction::Pop(d) => format!("R{} ← POP", d),
+        Instruction::Branch(..) | Instruction::Bl(_) => unreachable!("branches are not assignments"),
+    }
+}
+
+struct Decompiler<'a> {
+    items: Vec<Item<'a>>,
+    // Branch targets are the line after a label; this maps them back to it.
+    labels: HashMap<usize, &'a str>,
+    references: HashMap<&'a str, usize>,
+    gotos: HashSet<String>,
+}
+
+impl<'a> Decompiler<'a> {
+    fn target(&self, target: usize) -> &'a str {
+        self.labels[&target]
+    }
+
+    fn position(&self, name: &str, from: usize, to: usize) -> Option<usize> {
+        (from..to).find(|&index| matches!(self.items[index], Item::Label(label) if label == name))
+    }
+
+    // `CMP` immediately followed by a conditional branch: the condition
+    // under which the branch is taken, and where it goes.
+    fn test(&self, index: usize) -> Option<(Instruction, Condition, &'a str)> {
+        match (self.items.get(index), self.items.get(index + 1)) {
+            (Some(Item::Code(cmp @ Instruction::Cmp(..))), Some(Item::Code(Instruction::Branch(condition, target))))
+                if *condition != Condition::Always =>
+            {
+                Some((*cmp, *condition, self.target(*target)))
+            }
+            _ => None,
+        }
+    }
+
+    fn is_jump_to(&self, index: usize, name: &str) -> bool {
+        match self.items.get(index) {
+            Some(Item::Code(Instruction::Branch(Condition::Always, target))) => self.target(*target) == name,
+            _ => false,
+        }
+    }
+
+    fn condition(cmp: Instruction, condition: Condition, negated: bool) -> String {
+        let Instruction::Cmp(n, op) = cmp else {
+            unreachable!("conditions come from CMP");
+        };
+        format!("R{} {} {}", n, relation(condition, negated), operand(op))
+    }
+
+    // Recovers structures from `items[from..to]`, trying each pattern in turn
+    // and falling back to a single statement.
+    fn structure(&mut self, from: usize, to: usize) -> Vec<Statement> {
+        let mut statements = vec![];
+        let mut index = from;
+        while index < to {
+            let (statement, next) = self.recover(index, to);
+            statements.extend(statement);
+            index = next;
+        }
+        statements
+    }
+
+    fn recover(&mut self, index: usize, to: usize) -> (Vec<Statement>, usize) {
+        if let Item::Label(top) = self.items[index] {
+            // top: CMP; Bcc exit; body; B top; exit:
+            if let Some((cmp, condition, exit)) = self.test(index + 1)
+                && let Some(end) = self.position(exit, index + 3, to)
+                && self.is_jump_to(end - 1, top)
+            {
+                let body = self.structure(index + 3, end - 1);
+                let statement = Statement::While(Self::condition(cmp, condition, true), body);
+                return (vec![Statement::Label(top.to_string()), statement], end);
+            }
+            // top: body; CMP; Bcc top
+            if let Some(end) = (index + 2..to).rev().find(|&end| {
+                self.test(end).is_some_and(|(_, _, target)| target == top) && end + 1 < to
+            }) {
+                let (cmp, condition, _) = self.test(end).unwrap();
+                let body = self.structure(index + 1, end);
+                let statement = Statement::Repeat(body, Self::condition(cmp, condition, true));
+                return (vec![Statement::Label(top.to_string()), statement], end + 2);
+            }
+        }
+
+        if let Some((cmp, condition, skip)) = self.test(index)
+            && let Some(middle) = self.position(skip, index + 2, to)
+        {
+            let test = Self::condition(cmp, condition, true);
+            // CMP; Bcc else; then; B end; else: otherwise; end:
+            if middle > index + 2
+                && self.references[skip] == 1
+                && let Item::Code(Instruction::Branch(Condition::Always, target)) = self.items[middle - 1]
+                && let Some(end) = self.position(self.target(target), middle + 1, to)
+            {
+                let then = self.structure(index + 2, middle - 1);
+                let otherwise = self.structure(middle + 1, end);
+                return (vec![Statement::If(test, then, otherwise)], end);
+            }
+            // CMP; Bcc end; then; end:
+            let then = self.structure(index + 2, middle);
+            return (vec![Statement::If(test, then, vec![])], middle);
+        }
+
+        let statement = match self.items[index] {
+            Item::Label(name) => Statement::Label(name.to_string()),
+            Item::Code(Instruction::Branch(condition, target)) => {
+                let name = self.target(target).to_string();
+                self.gotos.insert(name.clone());
+                match (condition, self.previous_cmp(index)) {
+                    (Condition::Always, _) => Statement::Line(format!("GOTO {}", name)),
+                    (_, Some(cmp)) => Statement::If(
+                        Self::condition(cmp, condition, false),
+                        vec![Statement::Line(format!("GOTO {}", name))],
+                        vec![],
+                    ),
+                    (_, None) => Statement::Line(format!("IF last CMP gave {} THEN GOTO {}", relation(condition, false), name)),
+                }
+            }
+            Item::Code(Instruction::Bl(target)) => {
+                let name = self.target(target).to_string();
+                self.gotos.insert(name.clone());
+                Statement::Line(format!("CALL {}", name))
+            }
+            Item::Code(instruction) => Statement::Line(assignment(instruction)),
+        };
+        (vec![statement], index + 1)
+    }
+
+    // The CMP a conditional branch tests, if it is in the same straight-line
+    // run of code.
+    fn previous_cmp(&self, index: usize) -> Option<Instruction> {
+        self.items[..index].iter().rev().find_map(|item| match item {
+            Item::Code(cmp @ Instruction::Cmp(..)) => Some(Some(*cmp)),
+            Item::Code(Instruction::Branch(Condition::Always, _)) | Item::Label(_) => Some(None),
+            _ => None,
+        })?
+    }
+
+    fn print(&self, statements: &[Statement], depth: usize, out: &mut String) {
+        let indent = INDENT.repeat(depth);
+        for statement in statements {
+            match statement {
+                Statement::Line(line) => out.push_str(&format!("{}{}\n", indent, line)),
+                Statement::Label(name) if self.gotos.contains(name) => out.push_str(&format!("{}:\n", name)),
+                Statement::Label(_) => {}
+                Statement::If(condition, then, otherwise) => {
+                    out.push_str(&format!("{}IF {} THEN\n", indent, condition));
+                    self.print(then, depth + 1, out);
+                    if !otherwise.is_empty() {
+                        out.push_str(&format!("{}ELSE\n", indent));
+                        self.print(otherwise, depth + 1, out);
+                    }
+                    out.push_str(&format!("{}ENDIF\n", indent));
+                }
+                Statement::While(condition, body) => {
+                    out.push_str(&format!("{}WHILE {}\n", indent, condition));
+                    self.print(body, depth + 1, out);
+                    out.push_str(&format!("{}ENDWHILE\n", indent));
+                }
+                Statement::Repeat(body, condition) => {
+                    out.push_str(&format!("{}REPEAT\n", indent));
+                    self.print(body, depth + 1, out);
+                    out.push_str(&format!("{}UNTIL {}\n", indent, condition));
+                }
+            }
+        }
+    }
+}
+
+// Rewrites a program as AQA-style pseudocode. Registers keep their names and
+// each memory address becomes a variable `mem<address>`. Flow that matches no
+// `IF`, `WHILE` or `REPEAT` pattern is written with `GOTO` and labels.
+pub fn decompile(int: &Interpreter) -> Result<String, (usize, Trap)> {
+    let mut flow = Flow::new(&int.source, &int.jump_map, int.extensions);
+    if !flow.errors.is_empty() {
+        return Err(flow.errors.remove(0));
+    }
+
+    let mut decompiler = Decompiler {
+        items: vec![],
+        labels: HashMap::new(),
+        references: HashMap::new(),
+        gotos: HashSet::new(),
+    };
+    for (index, line) in int.source.iter().enumerate() {
+        if let Some(name) = label(line) {
+            decompiler.items.push(Item::Label(name));
+            decompiler.labels.insert(index + 1, name);
+        } else if let Some(instruction) = flow.instructions[index] {
+            decompiler.items.push(Item::Code(instruction));
+        }
+    }
+    for item in &decompiler.items {
+        if let Item::Code(Instruction::Branch(_, target)) = item {
+            *decompiler.references.entry(decompiler.target(*target)).or_insert(0) += 1;
+        }
+    }
+    // Running off the end and a final HALT both just end the pseudocode.
+    if let Some(Item::Code(Instruction::Halt)) = decompiler.items.last() {
+        decompiler.items.pop();
+    }
+
+    let statements = decompiler.structure(0, decompiler.items.len());
+    let mut pseudocode = String::new();
+    decompiler.print(&statements, 0, &mut pseudocode);
+    Ok(pseudocode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Extensions;
+
+    fn pseudocode(source: &str) -> String {
+        let mut int = Interpreter::new(source.to_string());
+        int.extensions = Extensions::ALL;
+        decompile(&int).unwrap()
+    }
+
+    #[test]
+    fn while_loop() {
+        let source = "MOV R0, #0\ntop:\nCMP R0, #10\nBGT done\nADD R0, R0, #1\nB top\ndone:\nSTR R0, 20\nHALT";
+        assert_eq!(
+            pseudocode(source),
+            "R0 ← 0\nWHILE R0 ≤ 10\n    R0 ← R0 + 1\nENDWHILE\nmem20 ← R0\n"
+        );
+    }
+
+    #[test]
+    fn repeat_loop() {
+        let source = "LDR R1, 40\nloop:\nLSL R1, R1, #1\nCMP R1, #100\nBLT loop\nHALT";
+        assert_eq!(pseudocode(source), "R1 ← mem40\nREPEAT\n    R1 ← R1 << 1\nUNTIL R1 ≥ 100\n");
+    }
+
+    #[test]
+    fn if_else() {
+        let source = "CMP R0, R1\nBEQ same\nMOV R2, #1\nB end\nsame:\nMOV R2, #2\nend:\nHALT";
+        assert_eq!(
+            pseudocode(source),
+            "IF R0 ≠ R1 THEN\n    R2 ← 1\nELSE\n    R2 ← 2\nENDIF\n"
+        );
+        assert_eq!(pseudocode("CMP R0, #0\nBNE skip\nMVN R0, R0\nskip:\nHALT"), "IF R0 = 0 THEN\n    R0 ← NOT R0\nENDIF\n");
+    }
+
+    #[test]
+    fn nested() {
+        let source = "top:\nCMP R0, #5\nBEQ out\nCMP R0, #2\nBNE odd\nSUB R1, R1, #1\nodd:\nADD R0, R0, #1\nB top\nout:\nHALT";
+        assert_eq!(
+            pseudocode(source),
+            "WHILE R0 ≠ 5\n    IF R0 = 2 THEN\n        R1 ← R1 - 1\n    ENDIF\n    R0 ← R0 + 1\nENDWHILE\n"
+        );
+    }
+
+    #[test]
+    fn goto_fallback() {
+        let source = "CMP R0, #0\nBEQ a\nBGT b\nHALT\na:\nMOV R1, #1\nb:\nMOV R2, #2\nB a";
+        assert_eq!(
+            pseudocode(source),
+            "IF R0 ≠ 0 THEN\n    IF R0 > 0 THEN\n        GOTO b\n    ENDIF\n    STOP\nENDIF\na:\nR1 ← 1\nb:\nR2 ← 2\nGOTO a\n"
+        );
+    }
+
+    #[test]
+    fn indexed() {
+        let source = "LDR R0, [R1]\nSTR R0, [R1, #-1]\nLDR R2, [R1, #4]\nHALT";
+        assert_eq!(pseudocode(source), "R0 ← mem[R1]\nmem[R1 - 1] ← R0\nR2 ← mem[R1 + 4]\n");
+    }
+
+    #[test]
+    fn subroutines() {
+        let source = "BL square\nHALT\nsquare:\nPUSH R1\nMOV R1, R0\nPOP R1\nRET";
+        assert_eq!(pseudocode(source), "CALL square\nSTOP\nsquare:\nPUSH R1\nR1 ← R0\nR1 ← POP\nRETURN\n");
+    }
+}
@@ -0,0 +1,219 @@
+use crate::Trap;
+use crate::console::Console;
+
+// Something mapped into memory so `LDR` and `STR` reach it instead of `mem`.
+// To add a device, implement this trait and list it in `BUILTIN` and `create`.
+pub trait Device {
+    // How many words from the base address the device answers to.
+    fn size(&self) -> usize {
+        1
+    }
+
+    fn read(&mut self, offset: usize, console: &mut Console) -> Result<i32, Trap>;
+
+    fn write(&mut self, offset: usize, value: i32, console: &mut Console) -> Result<(), Trap>;
+
+    // Called after every instruction.
+    fn tick(&mut self) {}
+
+    // Called when the machine is reset.
+    fn reset(&mut self) {}
+}
+
+// Device names and the address each is mapped to unless another is given.
+pub const BUILTIN: [(&str, usize); 4] = [("console", 240), ("keyboard", 241), ("timer", 242), ("random", 243)];
+
+// Writing a value prints the character with that code. Reads give 0.
+struct ConsoleOut;
+
+impl Device for ConsoleOut {
+    fn read(&mut self, _: usize, _: &mut Console) -> Result<i32, Trap> {
+        Ok(0)
+    }
+
+    fn write(&mut self, _: usize, value: i32, console: &mut Console) -> Result<(), Trap> {
+        console.write_char(value);
+        Ok(())
+    }
+}
+
+// Reading takes the next number from the same input as `INP`. Writes are ignored.
+struct Keyboard;
+
+impl Device for Keyboard {
+    fn read(&mut self, _: usize, console: &mut Console) -> Result<i32, Trap> {
+        console.read()
+    }
+
+    fn write(&mut self, _: usize, _: i32, _: &mut Console) -> Result<(), Trap> {
+        Ok(())
+    }
+}
+
+// Counts instructions. Writing sets the count, so a program can time a section
+// by clearing it first.
+struct Timer {
+    count: i32,
+}
+
+impl Device for Timer {
+    fn read(&mut self, _: usize, _: &mut Console) -> Result<i32, Trap> {
+        Ok(self.count)
+    }
+
+    fn write(&mut self, _: usize, value: i32, _: &mut Console) -> Result<(), Trap> {
+        self.count = value;
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        self.count = self.count.wrapping_add(1);
+    }
+
+    fn reset(&mut self) {
+        self.count = 0;
+    }
+}
+
+// A xorshift generator giving non-negative numbers. It always starts from the
+// same seed so runs can be repeated; writing a value reseeds it.
+struct Random {
+    state: u32,
+}
+
+const SEED: u32 = 2463534242;
+
+impl Device for Random {
+    fn read(&mut self, _: usize, _: &mut Console) -> Result<i32, Trap> {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        Ok((self.state & 0x7fff_ffff) as i32)
+    }
+
+    fn write(&mut self, _: usize, value: i32, _: &mut Console) -> Result<(), Trap> {
+        // Zero would make xorshift return zero forever.
+        self.state = if value == 0 { SEED } else { value as u32 };
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.state = SEED;
+    }
+}
+
+fn create(name: &str) -> Option<Box<dyn Device>> {
+    match name {
+        "console" => Some(Box::new(ConsoleOut)),
+        "keyboard" => Some(Box::new(Keyboard)),
+        "timer" => Some(Box::new(Timer { count: 0 })),
+        "random" => Some(Box::new(Random { state: SEED })),
+        _ => None,
+    }
+}
+
+// The devices attached to a machine and the addresses they are mapped to.
+#[derive(Default)]
+pub struct Devices {
+    mapped: Vec<(String, usize, Box<dyn Device>)>,
+}
+
+impl Devices {
+    // Parses a comma-separated list such as `console,timer@200`. Devices
+    // without an address go to their usual one.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut devices = Devices::default();
+        for item in spec.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let (name, address) = match item.split_once('@') {
+                Some((name, address)) => {
+                    let address = address.parse().map_err(|_| format!("invalid device address '{}'", address))?;
+                    (name, Some(address))
+                }
+                None => (item, None),
+            };
+            let device = create(name).ok_or_else(|| format!("unknown device '{}'", name))?;
+            let address = address.unwrap_or_else(|| BUILTIN.iter().find(|(builtin, _)| *builtin == name).unwrap().1);
+            devices.attach(name, address, device)?;
+        }
+        Ok(devices)
+    }
+
+    pub fn attach(&mut self, name: &str, base: usize, device: Box<dyn Device>) -> Result<(), String> {
+        let Some(end) = base.checked_add(device.size()).filter(|&end| end <= 256) else {
+            return Err(format!("device '{}' at {} does not fit in memory", name, base));
+        };
+        if let Some((other, ..)) = self.mapped.iter().find(|(_, other, device)| base < other + device.size() && *other < end) {
+            return Err(format!("device '{}' at {} overlaps '{}'", name, base, other));
+        }
+        self.mapped.push((name.to_string(), base, device));
+        Ok(())
+    }
+
+    // The device answering to an address, and the offset into it.
+    pub fn get(&mut self, address: usize) -> Option<(&mut (dyn Device + 'static), usize)> {
+        self.mapped
+            .iter_mut()
+            .find(|(_, base, device)| (*base..base + device.size()).contains(&address))
+            .map(|(_, base, device)| (device.as_mut(), address - *base))
+    }
+
+    // The name of the device mapped to an address, if there is one.
+    pub fn name(&self, address: usize) -> Option<&str> {
+        self.mapped
+            .iter()
+            .find(|(_, base, device)| (*base..base + device.size()).contains(&address))
+            .map(|(name, ..)| name.as_str())
+    }
+
+    pub fn tick(&mut self) {
+        for (_, _, device) in &mut self.mapped {
+            device.tick();
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for (_, _, device) in &mut self.mapped {
+            device.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses() {
+        let devices = Devices::parse("console, timer@200").unwrap();
+        assert_eq!((devices.name(240), devices.name(200), devices.name(241)), (Some("console"), Some("timer"), None));
+        assert_eq!(Devices::parse("lamp").err(), Some(String::from("unknown device 'lamp'")));
+        assert_eq!(Devices::parse("timer@256").err(), Some(String::from("device 'timer' at 256 does not fit in memory")));
+        assert_eq!(
+            Devices::parse("timer@18446744073709551615").err(),
+            Some(String::from("device 'timer' at 18446744073709551615 does not fit in memory"))
+        );
+        assert_eq!(
+            Devices::parse("console@5,random@5").err(),
+            Some(String::from("device 'random' at 5 overlaps 'console'"))
+        );
+    }
+
+    #[test]
+    fn devices() {
+        let mut console = Console::new();
+        let mut devices = Devices::parse("console,timer,random").unwrap();
+        let (device, _) = devices.get(240).unwrap();
+        device.write(0, 'A' as i32, &mut console).unwrap();
+        assert_eq!(console.output, "A");
+
+        devices.tick();
+        devices.tick();
+        assert_eq!(devices.get(242).unwrap().0.read(0, &mut console), Ok(2));
+
+        let first = devices.get(243).unwrap().0.read(0, &mut console).unwrap();
+        assert!(first >= 0);
+        devices.reset();
+        assert_eq!(devices.get(243).unwrap().0.read(0, &mut console), Ok(first));
+        assert_eq!(devices.get(242).unwrap().0.read(0, &mut console), Ok(0));
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use crate::Trap;
+use crate::instruction::Condition;
+use crate::instruction::Extensions;
+use crate::instruction::Instruction;
+use crate::instruction::MemoryRef;
+use crate::instruction::Operand;
+use crate::instruction::code;
+use crate::instruction::decode;
+use crate::instruction::label;
+
+// Word layout, most significant bit first:
+//   [31:27] opcode  [26:23] Rd  [22:19] Rn  [18] immediate flag  [17:0] operand
+// The operand is a signed 18-bit immediate, a register number in the low four
+// bits, for LDR/STR an 8-bit memory address, or for branches a 9-bit address
+// so a branch can land just past the last of the 256 words. An indexed LDR or
+// STR sets the immediate flag, with the base register in Rn and the offset as
+// the immediate.
+// HALT is opcode 0, so running into cleared memory stops the machine.
+const OPCODES: [&str; 25] = [
+    "HALT", "LDR", "STR", "ADD", "SUB", "MOV", "CMP", "B", "BEQ", "BNE", "BGT", "BLT", "AND", "ORR", "EOR", "MVN",
+    "LSL", "LSR", "INP", "OUT", "OUTC", "BL", "RET", "PUSH", "POP",
+];
+const IMMEDIATE_BITS: u32 = 18;
+const IMMEDIATE_MIN: i32 = -(1 << (IMMEDIATE_BITS - 1));
+const IMMEDIATE_MAX: i32 = (1 << (IMMEDIATE_BITS - 1)) - 1;
+
+pub struct Image {
+    pub words: Vec<i32>,
+    // The source line each word was assembled from.
+    pub lines: Vec<usize>,
+}
+
+fn opcode(mnemonic: &str) -> u32 {
+    OPCODES.iter().position(|&name| name == mnemonic).unwrap() as u32
+}
+
+fn operand(operand: Operand) -> Result<u32, Trap> {
+    match operand {
+        Operand::Immediate(value) if (IMMEDIATE_MIN..=IMMEDIATE_MAX).contains(&value) => {
+            Ok(1 << IMMEDIATE_BITS | (value as u32 & ((1 << IMMEDIATE_BITS) - 1)))
+        }
+        Operand::Immediate(value) => Err(Trap::Syntax(format!(
+            "immediate #{} does not fit in {} bits",
+            value, IMMEDIATE_BITS
+        ))),
+        Operand::Register(register) => Ok(register as u32),
+    }
+}
+
+pub fn encode(instruction: &Instruction) -> Result<i32, Trap> {
+    let fields = |d: usize, n: usize, rest: u32| (d as u32) << 23 | (n as u32) << 19 | rest;
+    let body = match *instruction {
+        Instruction::Ldr(d, MemoryRef::Direct(address)) | Instruction::Str(d, MemoryRef::Direct(address)) => {
+            fields(d, 0, address as u32)
+        }
+        Instruction::Ldr(d, MemoryRef::Indexed(n, offset)) | Instruction::Str(d, MemoryRef::Indexed(n, offset)) => {
+            fields(d, n, operand(Operand::Immediate(offset))?)
+        }
+        Instruction::Add(d, n, op)
+        | Instruction::Sub(d, n, op)
+        | Instruction::And(d, n, op)
+        | Instruction::Orr(d, n, op)
+        | Instruction::Eor(d, n, op)
+        | Instruction::Lsl(d, n, op)
+        | Instruction::Lsr(d, n, op) => fields(d, n, operand(op)?),
+        Instruction::Mov(d, op) | Instruction::Mvn(d, op) => fields(d, 0, operand(op)?),
+        Instruction::Cmp(n, op) => fields(0, n, operand(op)?),
+        Instruction::Branch(_, target) | Instruction::Bl(target) => target as u32,
+        Instruction::Halt | Instruction::Ret => 0,
+        Instruction::Inp(d)
+        | Instruction::Out(d)
+        | Instruction::Outc(d)
+        | Instruction::Push(d)
+        | Instruction::Pop(d) => fields(d, 0, 0),
+    };
+    Ok((opcode(instruction.mnemonic()) << 27 | body) as i32)
+}
+
+pub fn decode_word(word: i32) -> Result<Instruction, Trap> {
+    let word = word as u32;
+    let d = (word >> 23 & 0xf) as usize;
+    let n = (word >> 19 & 0xf) as usize;
+    let address = (word & 0xff) as usize;
+    let target = (word & 0x1ff) as usize;
+    let op = if word >> IMMEDIATE_BITS & 1 == 1 {
+        // Shift up then arithmetic shift down to sign-extend the 18-bit field.
+        Operand::Immediate(((word << (32 - IMMEDIATE_BITS)) as i32) >> (32 - IMMEDIATE_BITS))
+    } else {
+        Operand::Register((word & 0xf) as usize)
+    };
+
+    let memory = match op {
+        Operand::Immediate(offset) => MemoryRef::Indexed(n, offset),
+        Operand::Register(_) => MemoryRef::Direct(address),
+    };
+
+    let instruction = match OPCODES.get((word >> 27) as usize) {
+        Some(&"HALT") => Instruction::Halt,
+        Some(&"LDR") => Instruction::Ldr(d, memory),
+        Some(&"STR") => Instruction::Str(d, memory),
+        Some(&"ADD") => Instruction::Add(d, n, op),
+        Some(&"SUB") => Instruction::Sub(d, n, op),
+        Some(&"MOV") => Instruction::Mov(d, op),
+        Some(&"CMP") => Instruction::Cmp(n, op),
+        Some(&"B") => Instruction::Branch(Condition::Always, target),
+        Some(&"BEQ") => Instruction::Branch(Condition::EQ, target),
+        Some(&"BNE") => Instruction::Branch(Condition::NE, target),
+        Some(&"BGT") => Instruction::Branch(Condition::GT, target),
+        Some(&"BLT") => Instruction::Branch(Condition::LT, target),
+        Some(&"AND") => Instruction::And(d, n, op),
+        Some(&"ORR") => Instruction::Orr(d, n, op),
+        Some(&"EOR") => Instruction::Eor(d, n, op),
+        Some(&"MVN") => Instruction::Mvn(d, op),
+        Some(&"LSL") => Instruction::Lsl(d, n, op),
+        Some(&"LSR") => Instruction::Lsr(d, n, op),
+        Some(&"INP") => Instruction::Inp(d),
+        Some(&"OUT") => Instruction::Out(d),
+        Some(&"OUTC") => Instruction::Outc(d),
+        Some(&"BL") => Instruction::Bl(target),
+        Some(&"RET") => Instruction::Ret,
+        Some(&"PUSH") => Instruction::Push(d),
+        Some(&"POP") => Instruction::Pop(d),
+        _ => return Err(Trap::Instruction(word as i32)),
+    };
+    Ok(instruction)
+}
+
+// Assembles source into words starting at address 0. Labels resolve to the
+// address of the next instruction rather than the next line.
+pub fn assemble(source: &[String], extensions: Extensions) -> Result<Image, (usize, Trap)> {
+    let mut address_map = HashMap::new();
+    let mut address = 0;
+    for line in source {
+        if let Some(label) = label(line) {
+            address_map.insert(label.to_string(), address);
+        } else if !code(line).is_empty() {
+            address += 1;
+        }
+    }
+
+    let mut image = Image {
+        words: vec![],
+        lines: vec![],
+    };
+    for (index, line) in source.iter().enumerate() {
+        let Some(instruction) = decode(line, &address_map, extensions).map_err(|trap| (index, trap))? else {
+            continue;
+        };
+        if image.words.len() == 256 {
+            return Err((index, Trap::Syntax(String::from("program does not fit in memory"))));
+        }
+        image.words.push(encode(&instruction).map_err(|trap| (index, trap))?);
+        image.lines.push(index);
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(source: &str) -> Vec<String> {
+        source.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let instructions = [
+            Instruction::Ldr(3, MemoryRef::Direct(200)),
+            Instruction::Str(15, MemoryRef::Direct(255)),
+            Instruction::Ldr(0, MemoryRef::Indexed(1, 0)),
+            Instruction::Str(2, MemoryRef::Indexed(3, -4)),
+            Instruction::Add(1, 2, Operand::Immediate(-5)),
+            Instruction::Sub(4, 5, Operand::Register(6)),
+            Instruction::Mov(7, Operand::Immediate(IMMEDIATE_MAX)),
+            Instruction::Cmp(8, Operand::Immediate(IMMEDIATE_MIN)),
+            Instruction::Branch(Condition::LT, 17),
+            Instruction::Mvn(9, Operand::Register(10)),
+            Instruction::Lsr(11, 12, Operand::Immediate(3)),
+            Instruction::Halt,
+            Instruction::Outc(13),
+            Instruction::Bl(99),
+            Instruction::Branch(Condition::Always, 256),
+            Instruction::Ret,
+            Instruction::Pop(14),
+        ];
+        for instruction in instructions {
+            assert_eq!(decode_word(encode(&instruction).unwrap()), Ok(instruction));
+        }
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(encode(&Instruction::Halt), Ok(0));
+        assert_eq!(encode(&Instruction::Ldr(1, MemoryRef::Direct(40))).unwrap() as u32, 0x0880_0028);
+        assert_eq!(encode(&Instruction::Add(2, 3, Operand::Immediate(1))).unwrap() as u32, 0x1918_0001 | 1 << 18);
+        assert_eq!(decode_word(-1), Err(Trap::Instruction(-1)));
+        assert!(encode(&Instruction::Mov(0, Operand::Immediate(IMMEDIATE_MAX + 1))).is_err());
+    }
+
+    #[test]
+    fn assembles_with_addresses() {
+        let image = assemble(&lines("MOV R0, #0\nloop:\nADD R0, R0, #1\n\nCMP R0, #3\nBLT loop\nHALT"), Extensions::default()).unwrap();
+        assert_eq!(image.lines, vec![0, 2, 4, 5, 6]);
+        assert_eq!(decode_word(image.words[3]), Ok(Instruction::Branch(Condition::LT, 1)));
+        assert_eq!(
+            assemble(&lines("HALT\nB nowhere"), Extensions::default()).err(),
+            Some((1, Trap::Label(String::from("nowhere"))))
+        );
+
+        // A full memory's last branch targets the address just past the end.
+        let full = format!("{}B end\nend:", "MOV R0, #1\n".repeat(255));
+        let image = assemble(&lines(&full), Extensions::default()).unwrap();
+        assert_eq!(decode_word(image.words[255]), Ok(Instruction::Branch(Condition::Always, 256)));
+    }
+}
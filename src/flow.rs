@@ -0,0 +1,142 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::Trap;
+use crate::instruction::Condition;
+use crate::instruction::Extensions;
+use crate::instruction::Instruction;
+use crate::instruction::decode;
+
+// Control flow between source lines, for the static analyses. Every line is a
+// node, including labels and blank lines, which just fall through. The line
+// one past the end stands for running off the end of the program.
+pub struct Flow {
+    pub instructions: Vec<Option<Instruction>>,
+    // Lines that failed to decode. They are treated as falling through.
+    pub errors: Vec<(usize, Trap)>,
+}
+
+impl Flow {
+    pub fn new(source: &[String], jump_map: &HashMap<String, usize>, extensions: Extensions) -> Self {
+        let mut flow = Flow {
+            instructions: vec![],
+            errors: vec![],
+        };
+        for (index, line) in source.iter().enumerate() {
+            match decode(line, jump_map, extensions) {
+                Ok(instruction) => flow.instructions.push(instruction),
+                Err(trap) => {
+                    flow.instructions.push(None);
+                    flow.errors.push((index, trap));
+                }
+            }
+        }
+        flow
+    }
+
+    // The end-of-program node.
+    pub fn end(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn successors(&self, index: usize) -> Vec<usize> {
+        match self.instructions.get(index) {
+            None => vec![],
+            Some(Some(Instruction::Halt | Instruction::Ret)) => vec![],
+            Some(Some(Instruction::Branch(Condition::Always, target))) => vec![*target],
+            Some(Some(Instruction::Branch(_, target) | Instruction::Bl(target))) => vec![*target, index + 1],
+            Some(_) => vec![index + 1],
+        }
+    }
+
+    pub fn predecessors(&self) -> Vec<Vec<usize>> {
+        let mut predecessors = vec![vec![]; self.end() + 1];
+        for index in 0..self.end() {
+            for successor in self.successors(index) {
+                predecessors[successor].push(index);
+            }
+        }
+        predecessors
+    }
+
+    // Splits the lines into basic blocks. A block starts at the first line, at
+    // each label and after each branch, HALT or RET. Trailing lines with neither
+    // instructions nor a label don't form a block.
+    pub fn blocks(&self, jump_map: &HashMap<String, usize>) -> Vec<Range<usize>> {
+        let mut leaders = BTreeSet::from([0, self.end()]);
+        leaders.extend(jump_map.values().map(|target| target - 1));
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            if matches!(instruction, Some(Instruction::Branch(..) | Instruction::Halt | Instruction::Ret)) {
+                leaders.insert(index + 1);
+            }
+        }
+        let leaders: Vec<usize> = leaders.into_iter().collect();
+        leaders
+            .windows(2)
+            .map(|pair| pair[0]..pair[1])
+            .filter(|block| {
+                jump_map.values().any(|target| target - 1 == block.start)
+                    || self.instructions[block.clone()].iter().any(Option::is_some)
+            })
+            .collect()
+    }
+
+    // Which nodes, including the end, can be reached from the first line.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.end() + 1];
+        let mut pending = vec![0];
+        while let Some(index) = pending.pop() {
+            if reachable[index] {
+                continue;
+            }
+            reachable[index] = true;
+            pending.extend(self.successors(index));
+        }
+        reachable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Interpreter;
+
+    fn flow(source: &str) -> Flow {
+        let int = Interpreter::new(source.to_string());
+        Flow::new(&int.source, &int.jump_map, Extensions::ALL)
+    }
+
+    #[test]
+    fn successors() {
+        let flow = flow("loop:\nCMP R0, #1\nBEQ loop\nB done\nHALT\ndone:\nHALT");
+        assert_eq!(flow.successors(0), vec![1]);
+        assert_eq!(flow.successors(2), vec![1, 3]);
+        assert_eq!(flow.successors(3), vec![6]);
+        assert_eq!(flow.successors(6), vec![]);
+        assert_eq!(flow.reachable(), vec![true, true, true, true, false, false, true, false]);
+        assert_eq!(flow.predecessors()[1], vec![0, 2]);
+    }
+
+    #[test]
+    fn calls() {
+        let flow = flow("BL sub\nHALT\nsub:\nRET");
+        assert_eq!((flow.successors(0), flow.successors(3)), (vec![3, 1], vec![]));
+        assert_eq!(flow.reachable(), vec![true, true, false, true, false]);
+    }
+
+    #[test]
+    fn blocks() {
+        let int = Interpreter::new(String::from("MOV R0, #0\nloop:\nADD R0, R0, #1\nBLT loop\nHALT\n\nend:\n"));
+        let flow = Flow::new(&int.source, &int.jump_map, Extensions::default());
+        assert_eq!(flow.blocks(&int.jump_map), vec![0..1, 1..4, 4..5, 6..7]);
+        assert_eq!(Flow::new(&[], &HashMap::new(), Extensions::default()).blocks(&HashMap::new()), vec![]);
+    }
+
+    #[test]
+    fn errors_fall_through() {
+        let flow = flow("MOV R0, 1\nB nowhere");
+        assert_eq!(flow.errors.len(), 2);
+        assert_eq!(flow.reachable(), vec![true, true, true]);
+    }
+}
@@ -0,0 +1,135 @@
+// Canonical source layout: labels flush left, instructions indented with the
+// mnemonic padded to a fixed column, operands separated by `, ` and trailing
+// comments lined up one column past the longest commented line.
+const INDENT: &str = "    ";
+const MNEMONIC_WIDTH: usize = 6;
+
+enum Line {
+    Blank,
+    Comment(String),
+    Code(String, Option<String>),
+}
+
+fn operand(token: &str) -> String {
+    let register = token.len() > 1
+        && token.starts_with(['r', 'R'])
+        && token[1..].chars().all(|c| c.is_ascii_digit());
+    if register { token.to_uppercase() } else { token.to_string() }
+}
+
+fn operands(text: &str) -> Vec<String> {
+    text.split([',', ' ', '\t'])
+        .filter(|token| !token.is_empty())
+        .map(operand)
+        .collect()
+}
+
+fn instruction(code: &str) -> String {
+    let (mnemonic, rest) = code.split_once(char::is_whitespace).unwrap_or((code, ""));
+    // `[Rn, #offset]` is a single operand, laid out like the operand list.
+    let memory = rest
+        .split_once('[')
+        .and_then(|(before, memory)| Some((before, memory.trim_end().strip_suffix(']')?)));
+    let operands = match memory {
+        Some((before, memory)) => {
+            let mut list = operands(before);
+            list.push(format!("[{}]", operands(memory).join(", ")));
+            list
+        }
+        None => operands(rest),
+    };
+    let mnemonic = mnemonic.to_uppercase();
+    if operands.is_empty() {
+        format!("{}{}", INDENT, mnemonic)
+    } else {
+        format!("{}{:<width$}{}", INDENT, mnemonic, operands.join(", "), width = MNEMONIC_WIDTH)
+    }
+}
+
+fn parse(source: &str) -> Vec<Line> {
+    let mut lines = vec![];
+    for line in source.lines() {
+        let (code, comment) = match line.split_once(';') {
+            Some((code, comment)) => (code.trim(), Some(format!("; {}", comment.trim()).trim_end().to_string())),
+            None => (line.trim(), None),
+        };
+        if code.is_empty() {
+            lines.push(comment.map_or(Line::Blank, Line::Comment));
+            continue;
+        }
+        // `label: instruction` is split so the label gets a line of its own.
+        match code.split_once(':') {
+            Some((label, rest)) if !rest.trim().is_empty() => {
+                lines.push(Line::Code(format!("{}:", label.trim()), None));
+                lines.push(Line::Code(instruction(rest.trim()), comment));
+            }
+            Some((label, _)) => lines.push(Line::Code(format!("{}:", label.trim()), comment)),
+            None => lines.push(Line::Code(instruction(code), comment)),
+        }
+    }
+    lines
+}
+
+pub fn format(source: &str) -> String {
+    let lines = parse(source);
+    let comment_column = lines
+        .iter()
+        .filter_map(|line| match line {
+            Line::Code(code, Some(_)) => Some(code.len() + 2),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    let mut formatted = String::new();
+    let mut blank = true;
+    for line in &lines {
+        match line {
+            // Runs of blank lines collapse to one, and none are kept at the start.
+            Line::Blank if blank => continue,
+            Line::Blank => formatted.push('\n'),
+            Line::Comment(comment) => formatted.push_str(&format!("{}\n", comment)),
+            Line::Code(code, None) => formatted.push_str(&format!("{}\n", code)),
+            Line::Code(code, Some(comment)) => {
+                formatted.push_str(&format!("{:<width$}{}\n", code, comment, width = comment_column))
+            }
+        }
+        blank = matches!(line, Line::Blank);
+    }
+    // Nor at the end.
+    while formatted.ends_with("\n\n") {
+        formatted.pop();
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_layout() {
+        let source = "\n\nmov r0,#0\n  loop:   ; count up\nadd  R0,r0 , #1\n\n\n  cmp R0, #10 ;limit\nblt loop\nhalt\n\n";
+        assert_eq!(
+            format(source),
+            "    MOV   R0, #0\nloop:              ; count up\n    ADD   R0, R0, #1\n\n    CMP   R0, #10  ; limit\n    BLT   loop\n    HALT\n"
+        );
+    }
+
+    #[test]
+    fn splits_labels_and_keeps_comments() {
+        assert_eq!(format("; header\nend: halt ; done"), "; header\nend:\n    HALT  ; done\n");
+        assert_eq!(format(";\nb   Loop"), ";\n    B     Loop\n");
+    }
+
+    #[test]
+    fn memory_references() {
+        assert_eq!(format("ldr r0,[r1]\nstr r0 , [ r2 ,#-1 ]"), "    LDR   R0, [R1]\n    STR   R0, [R2, #-1]\n");
+    }
+
+    #[test]
+    fn idempotent() {
+        let once = format("start: ldr r1,40\n str r1 , 41;copy\nHALT");
+        assert_eq!(format(&once), once);
+    }
+}
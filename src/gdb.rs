@@ -0,0 +1,448 @@
+use std::collections::HashSet;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+
+use crate::Compare;
+use crate::Interpreter;
+
+// Instructions run between checks for an interrupt from GDB while continuing.
+const CHUNK: usize = 1000;
+
+// GDB needs an architecture it knows, so the machine is described as ARM.
+// R0-R14 are r0-r12, sp and lr, the PC is pc, and the comparison is shown in
+// the cpsr flags. ARM reserves r15 for the PC, so R15 gets its own feature.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>arm</architecture>
+  <feature name="org.gnu.gdb.arm.core">
+    <reg name="r0" bitsize="32" regnum="0"/>
+    <reg name="r1" bitsize="32"/>
+    <reg name="r2" bitsize="32"/>
+    <reg name="r3" bitsize="32"/>
+    <reg name="r4" bitsize="32"/>
+    <reg name="r5" bitsize="32"/>
+    <reg name="r6" bitsize="32"/>
+    <reg name="r7" bitsize="32"/>
+    <reg name="r8" bitsize="32"/>
+    <reg name="r9" bitsize="32"/>
+    <reg name="r10" bitsize="32"/>
+    <reg name="r11" bitsize="32"/>
+    <reg name="r12" bitsize="32"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+    <reg name="lr" bitsize="32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="cpsr" bitsize="32" regnum="16"/>
+  </feature>
+  <feature name="org.ian.gpr">
+    <reg name="R15" bitsize="32" regnum="17"/>
+  </feature>
+</target>
+"#;
+const PC: usize = 15;
+const CPSR: usize = 16;
+const R15: usize = 17;
+const REGISTER_COUNT: usize = 18;
+
+// Condition flags in the cpsr. A comparison sets one of them.
+const N: u32 = 1 << 31;
+const Z: u32 = 1 << 30;
+const C: u32 = 1 << 29;
+
+// Signals reported in stop replies.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+pub enum Reply {
+    Send(String),
+    // Run until a breakpoint, the program halts or GDB interrupts.
+    Continue,
+    Close,
+}
+
+pub struct Stub {
+    int: Interpreter,
+    breakpoints: HashSet<usize>,
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_number(hex: &str) -> Option<usize> {
+    usize::from_str_radix(hex, 16).ok()
+}
+
+// `addr,length` as used by the memory and breakpoint packets.
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((parse_number(address)?, parse_number(length)?))
+}
+
+impl Stub {
+    pub fn new(int: Interpreter) -> Self {
+        Stub {
+            int,
+            breakpoints: HashSet::new(),
+        }
+    }
+
+    fn register(&self, number: usize) -> Option<u32> {
+        match number {
+            0..PC => Some(self.int.reg[number] as u32),
+            PC => Some(self.int.line_num as u32),
+            CPSR => Some(match self.int.cmp {
+                Compare::EQ => Z,
+                Compare::LT => N,
+                Compare::GT => C,
+                Compare::NONE => 0,
+            }),
+            R15 => Some(self.int.reg[15] as u32),
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, number: usize, value: u32) -> bool {
+        match number {
+            0..PC => self.int.reg[number] = value as i32,
+            PC => self.int.line_num = value as usize,
+            CPSR => {
+                self.int.cmp = if value & Z != 0 {
+                    Compare::EQ
+                } else if value & N != 0 {
+                    Compare::LT
+                } else if value & C != 0 {
+                    Compare::GT
+                } else {
+                    Compare::NONE
+                }
+            }
+            R15 => self.int.reg[15] = value as i32,
+            _ => return false,
+        }
+        true
+    }
+
+    // Memory is bytes to GDB: each word is four bytes, little-endian.
+    fn read_memory(&self, address: usize, length: usize) -> Option<Vec<u8>> {
+        if address.checked_add(length)? > self.int.mem.len() * 4 {
+            return None;
+        }
+        Some((address..address + length).map(|byte| self.int.mem[byte / 4].to_le_bytes()[byte % 4]).collect())
+    }
+
+    fn write_memory(&mut self, address: usize, data: &[u8]) -> bool {
+        if address.checked_add(data.len()).is_none_or(|end| end > self.int.mem.len() * 4) {
+            return false;
+        }
+        for (offset, byte) in data.iter().enumerate() {
+            let word = &mut self.int.mem[(address + offset) / 4];
+            let mut bytes = word.to_le_bytes();
+            bytes[(address + offset) % 4] = *byte;
+            *word = i32::from_le_bytes(bytes);
+        }
+        true
+    }
+
+    fn features(&self, annex: &str) -> String {
+        let Some((offset, length)) = annex.strip_prefix("target.xml:").and_then(parse_range) else {
+            return String::from("E00");
+        };
+        let rest = TARGET_XML.get(offset.min(TARGET_XML.len())..).unwrap_or("");
+        if rest.len() > length {
+            format!("m{}", &rest[..length])
+        } else {
+            format!("l{}", rest)
+        }
+    }
+
+    // Runs one instruction and says why execution stopped, or `None` if it
+    // can carry on.
+    fn tick(&mut self) -> Option<String> {
+        if !self.int.running {
+            return Some(String::from("W00"));
+        }
+        if self.int.tick().is_err() {
+            return Some(format!("S{:02x}", SIGILL));
+        }
+        if !self.int.running {
+            return Some(String::from("W00"));
+        }
+        None
+    }
+
+    pub fn step(&mut self) -> String {
+        self.tick().unwrap_or_else(|| format!("S{:02x}", SIGTRAP))
+    }
+
+    // Continues for a batch of instructions, returning the stop reply if the
+    // program stopped.
+    pub fn run_chunk(&mut self) -> Option<String> {
+        for _ in 0..CHUNK {
+            if let Some(reply) = self.tick() {
+                return Some(reply);
+            }
+            if self.breakpoints.contains(&self.int.line_num) {
+                return Some(format!("S{:02x}", SIGTRAP));
+            }
+        }
+        None
+    }
+
+    pub fn interrupted() -> String {
+        format!("S{:02x}", SIGINT)
+    }
+
+    pub fn packet(&mut self, packet: &str) -> Reply {
+        let ok = |done: bool| Reply::Send(String::from(if done { "OK" } else { "E01" }));
+        let (command, rest) = packet.split_at(packet.len().min(1));
+        match command {
+            "?" => Reply::Send(format!("S{:02x}", SIGTRAP)),
+            "g" => Reply::Send(
+                (0..REGISTER_COUNT)
+                    .map(|number| hex_bytes(&self.register(number).unwrap().to_le_bytes()))
+                    .collect(),
+            ),
+            "G" => {
+                let Some(bytes) = parse_hex_bytes(rest).filter(|bytes| bytes.len() == REGISTER_COUNT * 4) else {
+                    return ok(false);
+                };
+                for (number, chunk) in bytes.chunks(4).enumerate() {
+                    self.set_register(number, u32::from_le_bytes(chunk.try_into().unwrap()));
+                }
+                ok(true)
+            }
+            "p" => match parse_number(rest).and_then(|number| self.register(number)) {
+                Some(value) => Reply::Send(hex_bytes(&value.to_le_bytes())),
+                None => ok(false),
+            },
+            "P" => {
+                let set = rest.split_once('=').and_then(|(number, value)| {
+                    let bytes: [u8; 4] = parse_hex_bytes(value)?.try_into().ok()?;
+                    Some(self.set_register(parse_number(number)?, u32::from_le_bytes(bytes)))
+                });
+                ok(set == Some(true))
+            }
+            "m" => match parse_range(rest).and_then(|(address, length)| self.read_memory(address, length)) {
+                Some(bytes) => Reply::Send(hex_bytes(&bytes)),
+                None => ok(false),
+            },
+            "M" => {
+                let written = rest.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_range(range)?;
+                    let data = parse_hex_bytes(data).filter(|data| data.len() == length)?;
+                    Some(self.write_memory(address, &data))
+                });
+                ok(written == Some(true))
+            }
+            "s" => Reply::Send(self.step()),
+            "c" => Reply::Continue,
+            "Z" | "z" => {
+                let Some(("0", range)) = rest.split_once(',') else {
+                    // Only software breakpoints are supported.
+                    return Reply::Send(String::new());
+                };
+                let Some((address, _)) = parse_range(range) else {
+                    return ok(false);
+                };
+                if command == "Z" {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                ok(true)
+            }
+            "H" => ok(true),
+            "k" => Reply::Close,
+            // Acknowledged here; `serve` closes the connection after replying.
+            "D" => Reply::Send(String::from("OK")),
+            _ if packet.starts_with("qSupported") => Reply::Send(String::from("PacketSize=4000;qXfer:features:read+")),
+            _ if packet.starts_with("qXfer:features:read:") => {
+                Reply::Send(self.features(&packet["qXfer:features:read:".len()..]))
+            }
+            _ if packet == "qAttached" => Reply::Send(String::from("1")),
+            _ if packet == "qC" => Reply::Send(String::from("QC1")),
+            _ if packet == "qfThreadInfo" => Reply::Send(String::from("m1")),
+            _ if packet == "qsThreadInfo" => Reply::Send(String::from("l")),
+            // An empty reply tells GDB a packet isn't supported.
+            _ => Reply::Send(String::new()),
+        }
+    }
+}
+
+fn send<W: Write>(output: &mut W, data: &str) -> io::Result<()> {
+    let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    write!(output, "${}#{:02x}", data, checksum)?;
+    output.flush()
+}
+
+// Reads the next packet, acknowledging it, or `None` when GDB disconnects.
+// Acknowledgements from GDB and interrupts while stopped are skipped.
+fn receive(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut byte = [0];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'$' {
+            break;
+        }
+    }
+    let mut data = Vec::new();
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'#' {
+            break;
+        }
+        data.push(byte[0]);
+    }
+    let mut checksum = [0; 2];
+    stream.read_exact(&mut checksum)?;
+    stream.write_all(b"+")?;
+    Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+}
+
+// Whether GDB has sent Ctrl-C (0x03) while the program runs.
+fn interrupt_pending(stream: &mut TcpStream) -> io::Result<bool> {
+    stream.set_nonblocking(true)?;
+    let mut byte = [0];
+    let result = match stream.read(&mut byte) {
+        Ok(1) => Ok(byte[0] == 0x03),
+        Ok(_) => Ok(true),
+        Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(error) => Err(error),
+    };
+    stream.set_nonblocking(false)?;
+    result
+}
+
+fn serve(stub: &mut Stub, stream: &mut TcpStream) -> io::Result<()> {
+    while let Some(packet) = receive(stream)? {
+        match stub.packet(&packet) {
+            Reply::Send(reply) => {
+                send(stream, &reply)?;
+                if packet == "D" {
+                    return Ok(());
+                }
+            }
+            Reply::Continue => loop {
+                if let Some(reply) = stub.run_chunk() {
+                    send(stream, &reply)?;
+                    break;
+                }
+                if interrupt_pending(stream)? {
+                    send(stream, &Stub::interrupted())?;
+                    break;
+                }
+            },
+            Reply::Close => return Ok(()),
+        }
+    }
+    Ok(())
+}
+
+// Waits for one GDB connection on localhost and serves it.
+pub fn run(int: Interpreter, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("Waiting for GDB on 127.0.0.1:{}", listener.local_addr()?.port());
+    let (mut stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    serve(&mut Stub::new(int), &mut stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stub(source: &str) -> Stub {
+        Stub::new(Interpreter::new(source.to_string()))
+    }
+
+    fn reply(stub: &mut Stub, packet: &str) -> String {
+        match stub.packet(packet) {
+            Reply::Send(reply) => reply,
+            _ => panic!("no reply to {}", packet),
+        }
+    }
+
+    #[test]
+    fn registers() {
+        let mut stub = stub("MOV R1, #258\nCMP R1, #300\nHALT");
+        assert_eq!(reply(&mut stub, "s"), "S05");
+        assert_eq!(reply(&mut stub, "p1"), "02010000");
+        assert_eq!(reply(&mut stub, "pf"), "01000000");
+        reply(&mut stub, "s");
+        assert_eq!(reply(&mut stub, "p10"), "00000080");
+        let registers = reply(&mut stub, "g");
+        assert_eq!(registers.len(), REGISTER_COUNT * 8);
+        assert_eq!(&registers[8..16], "02010000");
+
+        assert_eq!(reply(&mut stub, "P11=ffffffff"), "OK");
+        assert_eq!(stub.int.reg[15], -1);
+        assert_eq!(reply(&mut stub, "P10=00000040"), "OK");
+        assert_eq!(stub.int.cmp, Compare::EQ);
+        assert_eq!(reply(&mut stub, "p20"), "E01");
+    }
+
+    #[test]
+    fn memory() {
+        let mut stub = stub("HALT");
+        assert_eq!(reply(&mut stub, "M8,2:3412"), "OK");
+        assert_eq!(stub.int.mem[2], 0x1234);
+        assert_eq!(reply(&mut stub, "m8,4"), "34120000");
+        assert_eq!(reply(&mut stub, "m3fe,4"), "E01");
+        assert_eq!(reply(&mut stub, "M3ff,1:ff"), "OK");
+        assert_eq!(stub.int.mem[255], 0xff << 24);
+        assert_eq!(reply(&mut stub, "mffffffffffffffff,2"), "E01");
+        assert_eq!(reply(&mut stub, "Mffffffffffffffff,2:0000"), "E01");
+    }
+
+    #[test]
+    fn breakpoints_and_continue() {
+        let mut stub = stub("MOV R0, #0\nloop:\nADD R0, R0, #1\nCMP R0, #3\nBLT loop\nHALT");
+        assert_eq!(reply(&mut stub, "Z0,4,4"), "OK");
+        assert!(matches!(stub.packet("c"), Reply::Continue));
+        assert_eq!(stub.run_chunk(), Some(String::from("S05")));
+        assert_eq!(stub.int.line_num, 4);
+        assert_eq!(reply(&mut stub, "z0,4,4"), "OK");
+        assert_eq!(stub.run_chunk(), Some(String::from("W00")));
+        assert_eq!(stub.int.reg[0], 3);
+        assert_eq!(reply(&mut stub, "Z1,4,4"), "");
+    }
+
+    #[test]
+    fn traps_and_queries() {
+        let mut stub = stub("MOV R0, 1");
+        assert_eq!(reply(&mut stub, "s"), "S04");
+        assert!(reply(&mut stub, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+        let xml = reply(&mut stub, "qXfer:features:read:target.xml:0,40");
+        assert_eq!(xml, format!("m{}", &TARGET_XML[..0x40]));
+        let xml = reply(&mut stub, &format!("qXfer:features:read:target.xml:{:x},1000", TARGET_XML.len() - 10));
+        assert_eq!(xml, format!("l{}", &TARGET_XML[TARGET_XML.len() - 10..]));
+        assert_eq!(reply(&mut stub, "vMustReplyEmpty"), "");
+    }
+
+    #[test]
+    fn framing() {
+        let mut output = Vec::new();
+        send(&mut output, "OK").unwrap();
+        assert_eq!(output, b"$OK#9a");
+    }
+}
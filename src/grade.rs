@@ -0,0 +1,286 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::Interpreter;
+use crate::Trap;
+use crate::instruction::Extensions;
+use crate::testcase;
+use crate::testcase::Case;
+use crate::testcase::Outcome;
+
+struct Submission {
+    student: String,
+    file: String,
+    syntax_errors: Vec<(usize, Trap)>,
+    outcomes: Vec<Outcome>,
+}
+
+impl Submission {
+    fn passed(&self) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|outcome| matches!(outcome, Outcome::Pass))
+            .count()
+    }
+
+    fn traps(&self) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|outcome| matches!(outcome, Outcome::Trap(..)))
+            .count()
+    }
+
+    fn timeouts(&self) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|outcome| matches!(outcome, Outcome::Timeout(_)))
+            .count()
+    }
+
+    fn score(&self) -> f64 {
+        if self.outcomes.is_empty() {
+            return 0.0;
+        }
+        100.0 * self.passed() as f64 / self.outcomes.len() as f64
+    }
+}
+
+// Every submission gets a fresh interpreter, so nothing one student's program
+// does can leak into the next.
+fn grade_file(path: &Path, cases: &[Case]) -> Submission {
+    let student = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let file = path.to_string_lossy().into_owned();
+
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            return Submission {
+                student,
+                file,
+                syntax_errors: vec![(0, Trap::Syntax(format!("could not read file: {}", error)))],
+                outcomes: cases
+                    .iter()
+                    .map(|_| Outcome::Fail(vec![String::from("file could not be read")]))
+                    .collect(),
+            };
+        }
+    };
+
+    let mut int = Interpreter::new(source);
+    // Lines are checked in the spec's dialect, so extensions it allows aren't
+    // counted as syntax errors. An undefined label is found without running
+    // anything, so it counts as one too.
+    int.extensions = testcase::dialect(cases);
+    let syntax_errors = int
+        .check()
+        .into_iter()
+        .filter(|(_, trap)| matches!(trap, Trap::Syntax(_) | Trap::Label(_)))
+        .collect();
+    let outcomes = cases.iter().map(|case| testcase::run_case(&mut int, case)).collect();
+
+    Submission {
+        student,
+        file,
+        syntax_errors,
+        outcomes,
+    }
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn write_csv<W: Write>(submissions: &[Submission], out: &mut W) {
+    writeln!(out, "student,file,passed,total,score,traps,timeouts,syntax_errors").unwrap();
+    for submission in submissions {
+        writeln!(
+            out,
+            "{},{},{},{},{:.1},{},{},{}",
+            csv_field(&submission.student),
+            csv_field(&submission.file),
+            submission.passed(),
+            submission.outcomes.len(),
+            submission.score(),
+            submission.traps(),
+            submission.timeouts(),
+            submission.syntax_errors.len()
+        )
+        .unwrap();
+    }
+}
+
+// One <testsuite> per student and one <testcase> per case, plus a syntax check
+// so files that never parse still show up as errors.
+fn write_junit<W: Write>(submissions: &[Submission], cases: &[Case], out: &mut W) {
+    let tests: usize = submissions.iter().map(|s| s.outcomes.len() + 1).sum();
+    let failures: usize = submissions.iter().map(|s| s.outcomes.len() - s.passed() - s.traps() - s.timeouts()).sum();
+    let errors: usize = submissions
+        .iter()
+        .map(|s| s.traps() + s.timeouts() + usize::from(!s.syntax_errors.is_empty()))
+        .sum();
+
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>").unwrap();
+    writeln!(out, "<testsuites name=\"ian grade\" tests=\"{}\" failures=\"{}\" errors=\"{}\">", tests, failures, errors).unwrap();
+    for submission in submissions {
+        let student = xml_escape(&submission.student);
+        writeln!(
+            out,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\">",
+            student,
+            submission.outcomes.len() + 1,
+            submission.outcomes.len() - submission.passed() - submission.traps() - submission.timeouts(),
+            submission.traps() + submission.timeouts() + usize::from(!submission.syntax_errors.is_empty())
+        )
+        .unwrap();
+
+        if submission.syntax_errors.is_empty() {
+            writeln!(out, "    <testcase classname=\"{}\" name=\"syntax check\"/>", student).unwrap();
+        } else {
+            let message: Vec<String> = submission
+                .syntax_errors
+                .iter()
+                .map(|(line, trap)| format!("line {}: {}", line, trap))
+                .collect();
+            writeln!(out, "    <testcase classname=\"{}\" name=\"syntax check\">", student).unwrap();
+            writeln!(out, "      <error type=\"syntax\" message=\"{}\"/>", xml_escape(&message.join("; "))).unwrap();
+            writeln!(out, "    </testcase>").unwrap();
+        }
+
+        for (case, outcome) in cases.iter().zip(&submission.outcomes) {
+            let name = xml_escape(&case.name);
+            let kind = match outcome {
+                Outcome::Pass => {
+                    writeln!(out, "    <testcase classname=\"{}\" name=\"{}\"/>", student, name).unwrap();
+                    continue;
+                }
+                Outcome::Fail(_) => "failure type=\"mismatch\"",
+                Outcome::Trap(..) => "error type=\"trap\"",
+                Outcome::Timeout(_) => "error type=\"timeout\"",
+            };
+            let message = xml_escape(&testcase::details(outcome).join("; "));
+            writeln!(out, "    <testcase classname=\"{}\" name=\"{}\">", student, name).unwrap();
+            writeln!(out, "      <{} message=\"{}\"/>", kind, message).unwrap();
+            writeln!(out, "    </testcase>").unwrap();
+        }
+        writeln!(out, "  </testsuite>").unwrap();
+    }
+    writeln!(out, "</testsuites>").unwrap();
+}
+
+// Grades every `.asm` file in `dir` against `spec`, writing the CSV and JUnit
+// reports to the given paths and a summary table to `out`.
+pub fn run<W: Write>(dir: &Path, spec: &str, dialect: Extensions, csv_path: &Path, junit_path: &Path, out: &mut W) -> Result<(), String> {
+    let cases = testcase::parse(spec, dialect)?;
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .map_err(|error| format!("could not read {}: {}", dir.display(), error))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "asm"))
+        .collect();
+    paths.sort();
+
+    let submissions: Vec<Submission> = paths.iter().map(|path| grade_file(path, &cases)).collect();
+
+    for submission in &submissions {
+        writeln!(
+            out,
+            "{:<24} {:>3}/{:<3} {:>6.1}%  traps {}  timeouts {}  syntax errors {}",
+            submission.student,
+            submission.passed(),
+            submission.outcomes.len(),
+            submission.score(),
+            submission.traps(),
+            submission.timeouts(),
+            submission.syntax_errors.len()
+        )
+        .unwrap();
+    }
+
+    let mut csv = vec![];
+    write_csv(&submissions, &mut csv);
+    fs::write(csv_path, csv).map_err(|error| format!("could not write {}: {}", csv_path.display(), error))?;
+
+    let mut junit = vec![];
+    write_junit(&submissions, &cases, &mut junit);
+    fs::write(junit_path, junit).map_err(|error| format!("could not write {}: {}", junit_path.display(), error))?;
+
+    writeln!(out, "Graded {} submissions, reports written to {} and {}", submissions.len(), csv_path.display(), junit_path.display()).unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = "limit 100\ncase two\nset 40 2\nexpect 41 4\ncase three\nset 40 3\nexpect 41 6";
+
+    fn submissions(spec: &str, files: &[(&str, &str)]) -> (Vec<Submission>, Vec<Case>) {
+        let dir = std::env::temp_dir().join(format!("ian-grade-{}-{}", std::process::id(), files[0].0));
+        fs::create_dir_all(&dir).unwrap();
+        let cases = testcase::parse(spec, Extensions::default()).unwrap();
+        let graded = files
+            .iter()
+            .map(|(name, source)| {
+                let path = dir.join(format!("{}.asm", name));
+                fs::write(&path, source).unwrap();
+                grade_file(&path, &cases)
+            })
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        (graded, cases)
+    }
+
+    #[test]
+    fn classifies_results() {
+        let (graded, _) = submissions(SPEC, &[
+            ("alice", "LDR R0, 40\nADD R0, R0, R0\nSTR R0, 41\nHALT"),
+            ("bob", "loop:\nB loop"),
+            ("carol", "LDR R0, 40\nSTR R0, 41\nHALT\nADDD R0, R0, R0"),
+            ("dan", "LDR R0, 40\nB nowhere"),
+        ]);
+        assert_eq!((graded[0].passed(), graded[0].score()), (2, 100.0));
+        assert_eq!(graded[1].timeouts(), 2);
+        assert_eq!(graded[2].passed(), 0);
+        assert_eq!(graded[2].syntax_errors.len(), 1);
+        assert_eq!(graded[3].syntax_errors, vec![(1, Trap::Label(String::from("nowhere")))]);
+    }
+
+    #[test]
+    fn spec_dialect() {
+        let spec = "dialect aqa+io\ncase echoes\ninput 5\noutput 5";
+        let (graded, _) = submissions(spec, &[("frank", "INP R0\nOUT R0\nHALT"), ("gina", "INP R0\nPUSH R0\nHALT")]);
+        assert_eq!((graded[0].passed(), graded[0].syntax_errors.len()), (1, 0));
+        assert_eq!(graded[1].syntax_errors.len(), 1);
+    }
+
+    #[test]
+    fn reports() {
+        let (graded, cases) = submissions(SPEC, &[("dave, jr", "LDR R0, 40\nSTR R0, 41\nHALT"), ("erin", "LDR R0, 999")]);
+        let mut csv = vec![];
+        write_csv(&graded, &mut csv);
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("student,file,passed,total,score,traps,timeouts,syntax_errors\n\"dave, jr\","));
+        assert!(csv.ends_with(",0,2,0.0,2,0,0\n"));
+
+        let mut junit = vec![];
+        write_junit(&graded, &cases, &mut junit);
+        let junit = String::from_utf8(junit).unwrap();
+        assert!(junit.contains("<testsuites name=\"ian grade\" tests=\"6\" failures=\"2\" errors=\"2\">"));
+        assert!(junit.contains("<failure type=\"mismatch\" message=\"mem 41: expected 4, got 2\"/>"));
+        assert!(junit.contains("<error type=\"trap\" message=\"trap at line 0: memory address '999' out of range\"/>"));
+    }
+}
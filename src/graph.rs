@@ -0,0 +1,116 @@
+use std::ops::Range;
+
+use crate::Interpreter;
+use crate::flow::Flow;
+use crate::instruction::Condition;
+use crate::instruction::Instruction;
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn node(blocks: &[Range<usize>], line: usize) -> String {
+    match blocks.iter().find(|block| block.contains(&line)) {
+        Some(block) => format!("b{}", block.start),
+        None => String::from("end"),
+    }
+}
+
+fn condition(condition: Condition) -> &'static str {
+    match condition {
+        Condition::Always => "",
+        Condition::EQ => "EQ",
+        Condition::NE => "NE",
+        Condition::GT => "GT",
+        Condition::LT => "LT",
+    }
+}
+
+// The control flow graph as Graphviz DOT, one node per basic block. Edges out
+// of a conditional branch are labelled with the condition when taken and its
+// negation when not. With `counts`, nodes and edges also show how often they
+// were executed in the interpreter's stats.
+pub fn dot(int: &Interpreter, counts: bool) -> String {
+    let flow = Flow::new(&int.source, &int.jump_map, int.extensions);
+    let blocks = flow.blocks(&int.jump_map);
+    let hits = |block: &Range<usize>| block.clone().map(|line| int.stats.line_hits[line]).max().unwrap_or(0);
+
+    let mut graph = String::from("digraph program {\n    node [shape=box, fontname=\"monospace\"];\n");
+    for block in &blocks {
+        let mut label = String::new();
+        for line in &int.source[block.clone()] {
+            if !line.trim().is_empty() {
+                label.push_str(&format!("{}\\l", dot_escape(line.trim())));
+            }
+        }
+        if counts {
+            label.push_str(&format!("executed {} times\\l", hits(block)));
+        }
+        graph.push_str(&format!("    b{} [label=\"{}\"];\n", block.start, label));
+    }
+
+    let mut reaches_end = false;
+    for block in &blocks {
+        let last = block.end - 1;
+        let mut edge = |line: usize, label: String| {
+            reaches_end |= line == flow.end();
+            let attributes = if label.is_empty() { String::new() } else { format!(" [label=\"{}\"]", label) };
+            graph.push_str(&format!("    b{} -> {}{};\n", block.start, node(&blocks, line), attributes));
+        };
+        match flow.instructions[last] {
+            Some(Instruction::Branch(Condition::Always, target)) => {
+                edge(target, if counts { hits(block).to_string() } else { String::new() })
+            }
+            Some(Instruction::Branch(branch, target)) => {
+                let (taken, not_taken) = int.stats.branches[last];
+                let (taken, not_taken) = if counts {
+                    (format!(" ({})", taken), format!(" ({})", not_taken))
+                } else {
+                    (String::new(), String::new())
+                };
+                edge(target, format!("{}{}", condition(branch), taken));
+                edge(last + 1, format!("not {}{}", condition(branch), not_taken));
+            }
+            Some(Instruction::Halt | Instruction::Ret) => {}
+            _ => edge(last + 1, if counts { hits(block).to_string() } else { String::new() }),
+        }
+    }
+    if reaches_end {
+        graph.push_str("    end [shape=oval];\n");
+    }
+    graph.push_str("}\n");
+    graph
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "MOV R0, #0\nloop:\nADD R0, R0, #1\nCMP R0, #3\nBLT loop\nSTR R0, 10\nHALT";
+
+    #[test]
+    fn blocks_and_edges() {
+        let graph = dot(&Interpreter::new(PROGRAM.to_string()), false);
+        assert!(graph.starts_with("digraph program {\n"));
+        assert!(graph.contains("    b0 [label=\"MOV R0, #0\\l\"];\n"));
+        assert!(graph.contains("    b1 [label=\"loop:\\lADD R0, R0, #1\\lCMP R0, #3\\lBLT loop\\l\"];\n"));
+        assert!(graph.contains("    b0 -> b1;\n"));
+        assert!(graph.contains("    b1 -> b1 [label=\"LT\"];\n    b1 -> b5 [label=\"not LT\"];\n"));
+        assert!(!graph.contains("b5 ->"));
+        assert!(!graph.contains("end"));
+    }
+
+    #[test]
+    fn counts_and_end() {
+        let mut int = Interpreter::new(PROGRAM.to_string());
+        while int.running {
+            int.tick().unwrap();
+        }
+        let graph = dot(&int, true);
+        assert!(graph.contains("BLT loop\\lexecuted 3 times\\l\"];\n"));
+        assert!(graph.contains("    b1 -> b1 [label=\"LT (2)\"];\n    b1 -> b5 [label=\"not LT (1)\"];\n"));
+
+        let graph = dot(&Interpreter::new(String::from("MOV R0, #1")), false);
+        assert!(graph.contains("    b0 -> end;\n    end [shape=oval];\n"));
+    }
+}
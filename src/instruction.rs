@@ -0,0 +1,535 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::Trap;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Operand {
+    Immediate(i32),
+    Register(usize),
+}
+
+// Where `LDR` and `STR` read or write. The AQA specification only has a
+// direct address; the indirect extension adds `[Rn]` and `[Rn, #offset]`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MemoryRef {
+    Direct(usize),
+    Indexed(usize, i32),
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Condition {
+    Always,
+    EQ,
+    NE,
+    GT,
+    LT,
+}
+
+// A decoded instruction. Branch targets are already resolved to the line (or
+// address) execution continues from.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Instruction {
+    Ldr(usize, MemoryRef),
+    Str(usize, MemoryRef),
+    Add(usize, usize, Operand),
+    Sub(usize, usize, Operand),
+    Mov(usize, Operand),
+    Cmp(usize, Operand),
+    Branch(Condition, usize),
+    And(usize, usize, Operand),
+    Orr(usize, usize, Operand),
+    Eor(usize, usize, Operand),
+    Mvn(usize, Operand),
+    Lsl(usize, usize, Operand),
+    Lsr(usize, usize, Operand),
+    Halt,
+    Inp(usize),
+    Out(usize),
+    Outc(usize),
+    Bl(usize),
+    Ret,
+    Push(usize),
+    Pop(usize),
+}
+
+// The stack extension keeps the stack pointer in R13 and return addresses in R14.
+pub const SP: usize = 13;
+pub const LR: usize = 14;
+
+// Instruction set extensions beyond the AQA specification, all off by default.
+// Which are on makes up the dialect a program is written in: strict `aqa`,
+// `extended` with all of them, or `aqa` plus some, such as `aqa+io+stack`.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Extensions {
+    pub io: bool,
+    pub stack: bool,
+    pub indirect: bool,
+}
+
+const EXTENSIONS: [&str; 3] = ["io", "stack", "indirect"];
+
+impl Extensions {
+    pub const ALL: Extensions = Extensions {
+        io: true,
+        stack: true,
+        indirect: true,
+    };
+
+    // Parses a dialect: `aqa`, `extended` or extension names, separated by
+    // commas or `+`. Anything listed is added to strict AQA, so `io,stack`,
+    // `aqa+io+stack` and `aqa, io, stack` are the same dialect.
+    pub fn parse(names: &str) -> Result<Self, String> {
+        let mut extensions = Extensions::default();
+        for name in names.split([',', '+']).map(str::trim).filter(|name| !name.is_empty()) {
+            match name {
+                "aqa" => {}
+                "extended" => extensions = Extensions::ALL,
+                "io" => extensions.io = true,
+                "stack" => extensions.stack = true,
+                "indirect" => extensions.indirect = true,
+                _ => return Err(format!("unknown dialect or extension '{}'", name)),
+            }
+        }
+        Ok(extensions)
+    }
+
+    // Every extension either dialect has.
+    pub fn union(self, other: Extensions) -> Extensions {
+        Extensions {
+            io: self.io || other.io,
+            stack: self.stack || other.stack,
+            indirect: self.indirect || other.indirect,
+        }
+    }
+
+    fn enabled(&self, name: &str) -> bool {
+        match name {
+            "io" => self.io,
+            "stack" => self.stack,
+            "indirect" => self.indirect,
+            _ => false,
+        }
+    }
+
+    // Traps if the instruction belongs to an extension that is off.
+    pub fn allow(&self, instruction: &Instruction) -> Result<(), Trap> {
+        match instruction.extension() {
+            Some(name) if !self.enabled(name) => {
+                Err(Trap::Syntax(format!("{} needs the {} extension", instruction.mnemonic(), name)))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Ldr(..) => "LDR",
+            Instruction::Str(..) => "STR",
+            Instruction::Add(..) => "ADD",
+            Instruction::Sub(..) => "SUB",
+            Instruction::Mov(..) => "MOV",
+            Instruction::Cmp(..) => "CMP",
+            Instruction::Branch(Condition::Always, _) => "B",
+            Instruction::Branch(Condition::EQ, _) => "BEQ",
+            Instruction::Branch(Condition::NE, _) => "BNE",
+            Instruction::Branch(Condition::GT, _) => "BGT",
+            Instruction::Branch(Condition::LT, _) => "BLT",
+            Instruction::And(..) => "AND",
+            Instruction::Orr(..) => "ORR",
+            Instruction::Eor(..) => "EOR",
+            Instruction::Mvn(..) => "MVN",
+            Instruction::Lsl(..) => "LSL",
+            Instruction::Lsr(..) => "LSR",
+            Instruction::Halt => "HALT",
+            Instruction::Inp(_) => "INP",
+            Instruction::Out(_) => "OUT",
+            Instruction::Outc(_) => "OUTC",
+            Instruction::Bl(_) => "BL",
+            Instruction::Ret => "RET",
+            Instruction::Push(_) => "PUSH",
+            Instruction::Pop(_) => "POP",
+        }
+    }
+
+    // The extension an instruction comes from, or `None` if it is in the specification.
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Instruction::Inp(_) | Instruction::Out(_) | Instruction::Outc(_) => Some("io"),
+            Instruction::Bl(_) | Instruction::Ret | Instruction::Push(_) | Instruction::Pop(_) => Some("stack"),
+            Instruction::Ldr(_, MemoryRef::Indexed(..)) | Instruction::Str(_, MemoryRef::Indexed(..)) => Some("indirect"),
+            _ => None,
+        }
+    }
+
+    // The registers an instruction reads. A compare reads its operands even
+    // though it only writes the flags. The stack pointer is left out, as it
+    // starts with a value rather than needing to be written first.
+    pub fn reads(&self) -> Vec<usize> {
+        let operand = |operand: &Operand| match operand {
+            Operand::Register(register) => Some(*register),
+            Operand::Immediate(_) => None,
+        };
+        match self {
+            Instruction::Ldr(_, MemoryRef::Indexed(n, _)) => vec![*n],
+            Instruction::Str(d, MemoryRef::Indexed(n, _)) => vec![*d, *n],
+            Instruction::Str(d, _) | Instruction::Out(d) | Instruction::Outc(d) | Instruction::Push(d) => vec![*d],
+            Instruction::Ret => vec![LR],
+            Instruction::Add(_, n, op)
+            | Instruction::Sub(_, n, op)
+            | Instruction::And(_, n, op)
+            | Instruction::Orr(_, n, op)
+            | Instruction::Eor(_, n, op)
+            | Instruction::Lsl(_, n, op)
+            | Instruction::Lsr(_, n, op)
+            | Instruction::Cmp(n, op) => [Some(*n), operand(op)].into_iter().flatten().collect(),
+            Instruction::Mov(_, op) | Instruction::Mvn(_, op) => operand(op).into_iter().collect(),
+            Instruction::Ldr(..)
+            | Instruction::Branch(..)
+            | Instruction::Halt
+            | Instruction::Inp(_)
+            | Instruction::Bl(_)
+            | Instruction::Pop(_) => vec![],
+        }
+    }
+
+    pub fn writes(&self) -> Option<usize> {
+        match self {
+            Instruction::Ldr(d, _)
+            | Instruction::Add(d, ..)
+            | Instruction::Sub(d, ..)
+            | Instruction::And(d, ..)
+            | Instruction::Orr(d, ..)
+            | Instruction::Eor(d, ..)
+            | Instruction::Lsl(d, ..)
+            | Instruction::Lsr(d, ..)
+            | Instruction::Mov(d, _)
+            | Instruction::Mvn(d, _)
+            | Instruction::Inp(d)
+            | Instruction::Pop(d) => Some(*d),
+            Instruction::Bl(_) => Some(LR),
+            Instruction::Str(..)
+            | Instruction::Cmp(..)
+            | Instruction::Branch(..)
+            | Instruction::Halt
+            | Instruction::Out(_)
+            | Instruction::Outc(_)
+            | Instruction::Ret
+            | Instruction::Push(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Immediate(value) => write!(f, "#{}", value),
+            Operand::Register(register) => write!(f, "R{}", register),
+        }
+    }
+}
+
+// The dialect's name, which `Extensions::parse` reads back.
+impl fmt::Display for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if *self == Extensions::ALL {
+            return write!(f, "extended");
+        }
+        write!(f, "aqa")?;
+        for name in EXTENSIONS.iter().filter(|name| self.enabled(name)) {
+            write!(f, "+{}", name)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MemoryRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryRef::Direct(address) => write!(f, "{}", address),
+            MemoryRef::Indexed(n, 0) => write!(f, "[R{}]", n),
+            MemoryRef::Indexed(n, offset) => write!(f, "[R{}, #{}]", n, offset),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mnemonic = self.mnemonic();
+        match self {
+            Instruction::Ldr(d, address) | Instruction::Str(d, address) => {
+                write!(f, "{} R{}, {}", mnemonic, d, address)
+            }
+            Instruction::Add(d, n, operand)
+            | Instruction::Sub(d, n, operand)
+            | Instruction::And(d, n, operand)
+            | Instruction::Orr(d, n, operand)
+            | Instruction::Eor(d, n, operand)
+            | Instruction::Lsl(d, n, operand)
+            | Instruction::Lsr(d, n, operand) => write!(f, "{} R{}, R{}, {}", mnemonic, d, n, operand),
+            Instruction::Mov(d, operand) | Instruction::Mvn(d, operand) | Instruction::Cmp(d, operand) => {
+                write!(f, "{} R{}, {}", mnemonic, d, operand)
+            }
+            Instruction::Branch(_, target) | Instruction::Bl(target) => write!(f, "{} {}", mnemonic, target),
+            Instruction::Halt | Instruction::Ret => write!(f, "{}", mnemonic),
+            Instruction::Inp(d)
+            | Instruction::Out(d)
+            | Instruction::Outc(d)
+            | Instruction::Push(d)
+            | Instruction::Pop(d) => write!(f, "{} R{}", mnemonic, d),
+        }
+    }
+}
+
+fn register(token: Option<&str>) -> Result<usize, Trap> {
+    let token = token.ok_or_else(|| Trap::Syntax(String::from("missing operand")))?;
+    token
+        .strip_prefix('R')
+        .and_then(|number| number.parse::<usize>().ok())
+        .filter(|&number| number < 16)
+        .ok_or_else(|| Trap::Syntax(format!("invalid register '{}'", token)))
+}
+
+fn register_comma(token: Option<&str>) -> Result<usize, Trap> {
+    let token = token.ok_or_else(|| Trap::Syntax(String::from("missing operand")))?;
+    let register_token = token
+        .strip_suffix(',')
+        .ok_or_else(|| Trap::Syntax(format!("expected ',' after '{}'", token)))?;
+    register(Some(register_token))
+}
+
+fn address(token: Option<&str>) -> Result<usize, Trap> {
+    let token = token.ok_or_else(|| Trap::Syntax(String::from("missing memory address")))?;
+    match token.parse::<usize>() {
+        Ok(address) if address < 256 => Ok(address),
+        Ok(_) => Err(Trap::Address(token.to_string())),
+        Err(_) => Err(Trap::Syntax(format!("invalid memory address '{}'", token))),
+    }
+}
+
+// A direct address, or `[Rn]` or `[Rn, #offset]`. The bracketed forms span
+// several whitespace-separated tokens, so this takes the rest of the line.
+fn memory_ref(tokens: &[&str]) -> Result<MemoryRef, Trap> {
+    let text = tokens.join(" ");
+    let Some(inner) = text.strip_prefix('[') else {
+        return address(tokens.first().copied()).map(MemoryRef::Direct);
+    };
+    let inner = inner
+        .strip_suffix(']')
+        .ok_or_else(|| Trap::Syntax(format!("expected ']' at the end of '{}'", text)))?;
+    let (base, offset) = match inner.split_once(',') {
+        Some((base, offset)) => (base.trim(), Some(offset.trim())),
+        None => (inner.trim(), None),
+    };
+    let offset = match offset {
+        Some(offset) => offset
+            .strip_prefix('#')
+            .and_then(|value| value.parse::<i32>().ok())
+            .ok_or_else(|| Trap::Syntax(format!("invalid offset '{}'", offset)))?,
+        None => 0,
+    };
+    Ok(MemoryRef::Indexed(register(Some(base))?, offset))
+}
+
+fn operand2(token: Option<&str>) -> Result<Operand, Trap> {
+    let token = token.ok_or_else(|| Trap::Syntax(String::from("missing operand")))?;
+    if let Some(value) = token.strip_prefix('#') {
+        value
+            .parse::<i32>()
+            .map(Operand::Immediate)
+            .map_err(|_| Trap::Syntax(format!("invalid immediate '{}'", token)))
+    } else if token.starts_with('R') {
+        register(Some(token)).map(Operand::Register)
+    } else {
+        Err(Trap::Syntax(format!("invalid operand '{}'", token)))
+    }
+}
+
+fn target(token: Option<&str>, jump_map: &HashMap<String, usize>) -> Result<usize, Trap> {
+    let label = token.ok_or_else(|| Trap::Syntax(String::from("missing label")))?;
+    jump_map
+        .get(label)
+        .copied()
+        .ok_or_else(|| Trap::Label(label.to_string()))
+}
+
+// The part of a line before any `;` comment, without surrounding whitespace.
+pub fn code(line: &str) -> &str {
+    line.split(';').next().unwrap().trim()
+}
+
+// The name defined by a label line such as `loop:`.
+pub fn label(line: &str) -> Option<&str> {
+    code(line).strip_suffix(':')
+}
+
+// Decodes one source line in the given dialect. Labels, comments and blank
+// lines decode to `None`.
+pub fn decode(line: &str, jump_map: &HashMap<String, usize>, extensions: Extensions) -> Result<Option<Instruction>, Trap> {
+    let line = code(line);
+    if line.ends_with(':') {
+        return Ok(None);
+    }
+    let mut line = line.split_whitespace();
+    let instruction = match line.next() {
+        Some("LDR") => Instruction::Ldr(register_comma(line.next())?, memory_ref(&line.collect::<Vec<_>>())?),
+        Some("STR") => Instruction::Str(register_comma(line.next())?, memory_ref(&line.collect::<Vec<_>>())?),
+        Some("ADD") => Instruction::Add(register_comma(line.next())?, register_comma(line.next())?, operand2(line.next())?),
+        Some("SUB") => Instruction::Sub(register_comma(line.next())?, register_comma(line.next())?, operand2(line.next())?),
+        Some("MOV") => Instruction::Mov(register_comma(line.next())?, operand2(line.next())?),
+        Some("CMP") => Instruction::Cmp(register_comma(line.next())?, operand2(line.next())?),
+        Some("B") => Instruction::Branch(Condition::Always, target(line.next(), jump_map)?),
+        Some("BEQ") => Instruction::Branch(Condition::EQ, target(line.next(), jump_map)?),
+        Some("BNE") => Instruction::Branch(Condition::NE, target(line.next(), jump_map)?),
+        Some("BGT") => Instruction::Branch(Condition::GT, target(line.next(), jump_map)?),
+        Some("BLT") => Instruction::Branch(Condition::LT, target(line.next(), jump_map)?),
+        Some("AND") => Instruction::And(register_comma(line.next())?, register_comma(line.next())?, operand2(line.next())?),
+        Some("ORR") => Instruction::Orr(register_comma(line.next())?, register_comma(line.next())?, operand2(line.next())?),
+        Some("EOR") => Instruction::Eor(register_comma(line.next())?, register_comma(line.next())?, operand2(line.next())?),
+        Some("MVN") => Instruction::Mvn(register_comma(line.next())?, operand2(line.next())?),
+        Some("LSL") => Instruction::Lsl(register_comma(line.next())?, register_comma(line.next())?, operand2(line.next())?),
+        Some("LSR") => Instruction::Lsr(register_comma(line.next())?, register_comma(line.next())?, operand2(line.next())?),
+        Some("HALT") => Instruction::Halt,
+        Some("INP") => Instruction::Inp(register(line.next())?),
+        Some("OUT") => Instruction::Out(register(line.next())?),
+        Some("OUTC") => Instruction::Outc(register(line.next())?),
+        Some("BL") => Instruction::Bl(target(line.next(), jump_map)?),
+        Some("RET") => Instruction::Ret,
+        Some("PUSH") => Instruction::Push(register(line.next())?),
+        Some("POP") => Instruction::Pop(register(line.next())?),
+        Some(mnemonic) => return Err(Trap::Syntax(format!("unknown instruction '{}'", mnemonic))),
+        None => return Ok(None),
+    };
+    extensions.allow(&instruction)?;
+    Ok(Some(instruction))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes() {
+        let jump_map = HashMap::from([(String::from("loop"), 3)]);
+        assert_eq!(decode("LDR R0, 40", &jump_map, Extensions::default()), Ok(Some(Instruction::Ldr(0, MemoryRef::Direct(40)))));
+        assert_eq!(
+            decode("ADD R1, R2, #-5", &jump_map, Extensions::default()),
+            Ok(Some(Instruction::Add(1, 2, Operand::Immediate(-5))))
+        );
+        assert_eq!(decode("CMP R3, R4", &jump_map, Extensions::default()), Ok(Some(Instruction::Cmp(3, Operand::Register(4)))));
+        assert_eq!(decode("BNE loop", &jump_map, Extensions::default()), Ok(Some(Instruction::Branch(Condition::NE, 3))));
+        assert_eq!(decode("loop:", &jump_map, Extensions::default()), Ok(None));
+        assert_eq!(decode("   ", &jump_map, Extensions::default()), Ok(None));
+        assert_eq!(decode("  loop:  ; top of loop", &jump_map, Extensions::default()), Ok(None));
+        assert_eq!(decode("; just a comment", &jump_map, Extensions::default()), Ok(None));
+        assert_eq!(decode("    HALT    ; stop", &jump_map, Extensions::default()), Ok(Some(Instruction::Halt)));
+    }
+
+    #[test]
+    fn registers_used() {
+        let add = Instruction::Add(1, 2, Operand::Register(3));
+        assert_eq!((add.reads(), add.writes()), (vec![2, 3], Some(1)));
+        let cmp = Instruction::Cmp(4, Operand::Immediate(0));
+        assert_eq!((cmp.reads(), cmp.writes()), (vec![4], None));
+        assert_eq!(Instruction::Str(5, MemoryRef::Direct(10)).reads(), vec![5]);
+        assert_eq!(Instruction::Str(5, MemoryRef::Indexed(6, 1)).reads(), vec![5, 6]);
+    }
+
+    #[test]
+    fn extensions() {
+        let extensions = Extensions::parse("io").unwrap();
+        assert!(extensions.io);
+        assert_eq!(Extensions::parse(""), Ok(Extensions::default()));
+        assert_eq!(Extensions::parse("io, gpu"), Err(String::from("unknown dialect or extension 'gpu'")));
+
+        let inp = decode("INP R2", &HashMap::new(), Extensions::ALL).unwrap().unwrap();
+        assert_eq!((inp.reads(), inp.writes()), (vec![], Some(2)));
+        assert_eq!(extensions.allow(&inp), Ok(()));
+        assert_eq!(
+            Extensions::default().allow(&inp),
+            Err(Trap::Syntax(String::from("INP needs the io extension")))
+        );
+        assert_eq!(Extensions::default().allow(&Instruction::Halt), Ok(()));
+        assert_eq!(
+            extensions.allow(&Instruction::Ret),
+            Err(Trap::Syntax(String::from("RET needs the stack extension")))
+        );
+
+        let bl = decode("BL sub", &HashMap::from([(String::from("sub"), 4)]), Extensions::ALL).unwrap().unwrap();
+        assert_eq!((bl, bl.reads(), bl.writes()), (Instruction::Bl(4), vec![], Some(LR)));
+        assert_eq!((Instruction::Ret.reads(), Instruction::Pop(3).writes()), (vec![LR], Some(3)));
+    }
+
+    #[test]
+    fn dialects() {
+        assert_eq!(Extensions::parse("aqa"), Ok(Extensions::default()));
+        assert_eq!(Extensions::parse("extended"), Ok(Extensions::ALL));
+        let io_stack = Extensions::parse("aqa+io+stack").unwrap();
+        assert_eq!(Extensions::parse("io, stack"), Ok(io_stack));
+        assert_eq!(
+            (Extensions::default().to_string(), io_stack.to_string(), Extensions::ALL.to_string()),
+            (String::from("aqa"), String::from("aqa+io+stack"), String::from("extended"))
+        );
+
+        // The parser itself rejects what the dialect leaves out.
+        assert_eq!(
+            decode("OUT R0 ; print", &HashMap::new(), Extensions::default()),
+            Err(Trap::Syntax(String::from("OUT needs the io extension")))
+        );
+        assert_eq!(decode("OUT R0", &HashMap::new(), io_stack), Ok(Some(Instruction::Out(0))));
+    }
+
+    #[test]
+    fn memory_refs() {
+        let jump_map = HashMap::new();
+        let ldr = decode("LDR R0, [R1]", &jump_map, Extensions::ALL).unwrap().unwrap();
+        assert_eq!((ldr, ldr.reads()), (Instruction::Ldr(0, MemoryRef::Indexed(1, 0)), vec![1]));
+        assert_eq!(
+            decode("STR R2, [R3, #-4] ; previous", &jump_map, Extensions::ALL),
+            Ok(Some(Instruction::Str(2, MemoryRef::Indexed(3, -4))))
+        );
+        assert_eq!(decode("LDR R0, [R1,#4]", &jump_map, Extensions::ALL), Ok(Some(Instruction::Ldr(0, MemoryRef::Indexed(1, 4)))));
+        assert_eq!(
+            decode("LDR R0, [R1, 4]", &jump_map, Extensions::ALL),
+            Err(Trap::Syntax(String::from("invalid offset '4'")))
+        );
+        assert_eq!(
+            decode("LDR R0, [R1", &jump_map, Extensions::ALL),
+            Err(Trap::Syntax(String::from("expected ']' at the end of '[R1'")))
+        );
+        assert_eq!(
+            Extensions::default().allow(&ldr),
+            Err(Trap::Syntax(String::from("LDR needs the indirect extension")))
+        );
+        assert_eq!(Extensions::parse("indirect").unwrap().allow(&ldr), Ok(()));
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(label("loop:"), Some("loop"));
+        assert_eq!(label("  end:   ; done"), Some("end"));
+        assert_eq!(label("B loop ; back:"), None);
+    }
+
+    #[test]
+    fn displays() {
+        let jump_map = HashMap::new();
+        for line in [
+            "LSL R3, R2, #1",
+            "MVN R0, R7",
+            "STR R15, 255",
+            "LDR R1, [R2]",
+            "STR R1, [R2, #3]",
+            "HALT",
+            "OUTC R4",
+            "PUSH R14",
+            "RET",
+        ] {
+            assert_eq!(decode(line, &jump_map, Extensions::ALL).unwrap().unwrap().to_string(), line);
+        }
+        assert_eq!(Instruction::Branch(Condition::GT, 7).to_string(), "BGT 7");
+    }
+}
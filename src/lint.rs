@@ -0,0 +1,265 @@
+use std::fmt;
+
+use crate::Interpreter;
+use crate::flow::Flow;
+use crate::instruction::Condition;
+use crate::instruction::Instruction;
+use crate::instruction::MemoryRef;
+use crate::instruction::label;
+
+// Warning codes are part of the interface: they appear in `; allow` comments,
+// so existing codes must never be renumbered.
+pub const SYNTAX: &str = "L000";
+pub const UNWRITTEN_REGISTER: &str = "L001";
+pub const UNREACHABLE: &str = "L002";
+pub const MISSING_HALT: &str = "L003";
+pub const BRANCH_WITHOUT_CMP: &str = "L004";
+pub const UNREAD_STORE: &str = "L005";
+pub const UNUSED_LABEL: &str = "L006";
+
+#[derive(PartialEq, Debug)]
+pub struct Warning {
+    pub line: usize,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {} {}", self.line, self.code, self.message)
+    }
+}
+
+// What is known on every path into a line: the registers that have been
+// written, and whether a CMP has run.
+#[derive(Clone, Copy, PartialEq)]
+struct State {
+    written: u16,
+    compared: bool,
+}
+
+impl State {
+    const UNKNOWN: State = State {
+        written: u16::MAX,
+        compared: true,
+    };
+
+    fn meet(self, other: State) -> State {
+        State {
+            written: self.written & other.written,
+            compared: self.compared && other.compared,
+        }
+    }
+
+    fn after(mut self, instruction: Option<&Instruction>) -> State {
+        if let Some(instruction) = instruction {
+            if let Some(register) = instruction.writes() {
+                self.written |= 1 << register;
+            }
+            self.compared |= matches!(instruction, Instruction::Cmp(..));
+        }
+        self
+    }
+}
+
+// Iterates to a fixed point from the empty state at the first line.
+fn states(flow: &Flow) -> Vec<State> {
+    let predecessors = flow.predecessors();
+    let mut states = vec![State::UNKNOWN; flow.end()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for index in 0..flow.end() {
+            let mut state = predecessors[index]
+                .iter()
+                .map(|&predecessor| match flow.instructions[predecessor] {
+                    // Returning from a call, any register may have been written.
+                    Some(Instruction::Bl(target)) if target != index => State::UNKNOWN,
+                    instruction => states[predecessor].after(instruction.as_ref()),
+                })
+                .fold(State::UNKNOWN, State::meet);
+            if index == 0 {
+                state = state.meet(State {
+                    written: 0,
+                    compared: false,
+                });
+            }
+            if state != states[index] {
+                states[index] = state;
+                changed = true;
+            }
+        }
+    }
+    states
+}
+
+// A line opts out of a warning with a comment such as `; allow L001 L005`.
+fn allowed(line: &str, code: &str) -> bool {
+    line.split_once(';')
+        .and_then(|(_, comment)| comment.split_once("allow"))
+        .is_some_and(|(_, codes)| codes.split([' ', ',']).any(|allowed| allowed == code))
+}
+
+pub fn lint(int: &Interpreter) -> Vec<Warning> {
+    let flow = Flow::new(&int.source, &int.jump_map, int.extensions);
+    let reachable = flow.reachable();
+    let states = states(&flow);
+    let mut warnings = vec![];
+    let mut warn = |line: usize, code: &'static str, message: String| {
+        warnings.push(Warning { line, code, message })
+    };
+
+    for (line, trap) in &flow.errors {
+        warn(*line, SYNTAX, trap.to_string());
+    }
+
+    let loaded: Vec<usize> = flow
+        .instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Some(Instruction::Ldr(_, MemoryRef::Direct(address))) => Some(*address),
+            _ => None,
+        })
+        .collect();
+    // An indexed load could read any address, so every store may be read.
+    let loads_anywhere = flow
+        .instructions
+        .iter()
+        .any(|instruction| matches!(instruction, Some(Instruction::Ldr(_, MemoryRef::Indexed(..)))));
+    let targets: Vec<usize> = flow
+        .instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Some(Instruction::Branch(_, target) | Instruction::Bl(target)) => Some(*target),
+            _ => None,
+        })
+        .collect();
+
+    let mut previous_reachable = true;
+    for (index, instruction) in flow.instructions.iter().enumerate() {
+        if let Some(name) = label(&int.source[index])
+            && !targets.contains(&(index + 1))
+        {
+            warn(index, UNUSED_LABEL, format!("label '{}' is never branched to", name));
+        }
+        let Some(instruction) = instruction else {
+            continue;
+        };
+
+        if !reachable[index] {
+            // Only the first line of each unreachable run is reported.
+            if previous_reachable {
+                warn(index, UNREACHABLE, String::from("unreachable code"));
+            }
+            previous_reachable = false;
+            continue;
+        }
+        previous_reachable = true;
+
+        let state = states[index];
+        for register in instruction.reads() {
+            if state.written & 1 << register == 0 {
+                warn(index, UNWRITTEN_REGISTER, format!("R{} may be read before it is written", register));
+            }
+        }
+        if let Instruction::Branch(condition, _) = instruction
+            && *condition != Condition::Always
+            && !state.compared
+        {
+            warn(index, BRANCH_WITHOUT_CMP, format!("{} may run before any CMP", instruction.mnemonic()));
+        }
+        // Stores to a device are how a program talks to it.
+        if let Instruction::Str(_, MemoryRef::Direct(address)) = instruction
+            && !loads_anywhere
+            && !loaded.contains(address)
+            && int.devices.name(*address).is_none()
+        {
+            warn(index, UNREAD_STORE, format!("address {} is stored to but never loaded", address));
+        }
+    }
+
+    // Any reachable line whose successor is the end can run off the program.
+    for index in (0..flow.end()).filter(|&index| reachable[index]) {
+        if flow.successors(index).contains(&flow.end()) {
+            warn(index, MISSING_HALT, String::from("execution can run past the end without HALT"));
+        }
+    }
+
+    warnings.retain(|warning| !allowed(&int.source[warning.line], warning.code));
+    warnings.sort_by_key(|warning| (warning.line, warning.code));
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Devices;
+
+    fn codes(source: &str) -> Vec<(usize, &'static str)> {
+        lint(&Interpreter::new(source.to_string()))
+            .iter()
+            .map(|warning| (warning.line, warning.code))
+            .collect()
+    }
+
+    #[test]
+    fn clean_program() {
+        let source = "MOV R0, #0\nloop:\nADD R0, R0, #1\nCMP R0, #10\nBLT loop\nSTR R0, 100\nLDR R1, 100\nHALT";
+        assert_eq!(codes(source), vec![]);
+    }
+
+    #[test]
+    fn extensions() {
+        let source = "INP R0\nOUT R0\nHALT";
+        assert_eq!(codes(source), vec![(0, SYNTAX), (1, SYNTAX)]);
+        let mut int = Interpreter::new(source.to_string());
+        int.extensions.io = true;
+        assert!(lint(&int).is_empty());
+    }
+
+    #[test]
+    fn calls() {
+        // R1 is written by the subroutine, so reading it after the call is fine.
+        let mut int = Interpreter::new(String::from("BL set\nMOV R0, R1\nHALT\nset:\nMOV R1, #1\nRET"));
+        int.extensions.stack = true;
+        assert!(lint(&int).is_empty());
+    }
+
+    #[test]
+    fn register_read_before_write() {
+        assert_eq!(codes("ADD R0, R1, #1\nHALT"), vec![(0, UNWRITTEN_REGISTER)]);
+        // Written on only one side of a branch.
+        let source = "CMP R0, #0\nBEQ skip\nMOV R1, #1\nskip:\nMOV R2, R1\nHALT";
+        assert_eq!(codes(source), vec![(0, UNWRITTEN_REGISTER), (4, UNWRITTEN_REGISTER)]);
+    }
+
+    #[test]
+    fn unreachable_and_missing_halt() {
+        assert_eq!(codes("B end\nMOV R0, #1\nMOV R1, #2\nend:\nHALT\nHALT"), vec![(1, UNREACHABLE), (5, UNREACHABLE)]);
+        assert_eq!(codes("MOV R0, #1"), vec![(0, MISSING_HALT)]);
+    }
+
+    #[test]
+    fn branch_without_cmp() {
+        let source = "MOV R0, #1\nloop:\nBEQ loop\nHALT";
+        assert_eq!(codes(source), vec![(2, BRANCH_WITHOUT_CMP)]);
+    }
+
+    #[test]
+    fn unread_store_and_unused_label() {
+        assert_eq!(codes("MOV R0, #1\nSTR R0, 7\nspare:\nHALT"), vec![(1, UNREAD_STORE), (2, UNUSED_LABEL)]);
+        let mut int = Interpreter::new(String::from("MOV R0, #65\nSTR R0, 240\nHALT"));
+        int.devices = Devices::parse("console").unwrap();
+        assert!(lint(&int).is_empty());
+        let mut int = Interpreter::new(String::from("MOV R0, #1\nSTR R0, 7\nMOV R1, #7\nLDR R2, [R1]\nHALT"));
+        int.extensions.indirect = true;
+        assert!(lint(&int).is_empty());
+    }
+
+    #[test]
+    fn suppression() {
+        assert_eq!(codes("MOV R0, #1\nSTR R0, 7 ; allow L005\nspare: ; allow L001, L006\nHALT"), vec![]);
+        let warnings = lint(&Interpreter::new(String::from("B nowhere")));
+        assert_eq!(warnings[0].to_string(), "line 0: L000 undefined label 'nowhere'");
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+
+use crate::Interpreter;
+use crate::instruction::Extensions;
+use crate::instruction::code;
+use crate::instruction::label;
+use crate::json::Json;
+use crate::json::object;
+use crate::lint;
+
+// Hover text for each mnemonic, in the same terms as the AQA specification.
+const MNEMONICS: [(&str, &str); 25] = [
+    ("LDR", "LDR Rd, <memory ref>\n\nLoad the value stored in the memory location into register d. The indirect extension also allows `[Rn]` and `[Rn, #offset]`."),
+    ("STR", "STR Rd, <memory ref>\n\nStore the value in register d into the memory location. The indirect extension also allows `[Rn]` and `[Rn, #offset]`."),
+    ("ADD", "ADD Rd, Rn, <operand2>\n\nAdd the value of operand2 to the value in register n and store the result in register d."),
+    ("SUB", "SUB Rd, Rn, <operand2>\n\nSubtract the value of operand2 from the value in register n and store the result in register d."),
+    ("MOV", "MOV Rd, <operand2>\n\nCopy the value of operand2 into register d."),
+    ("CMP", "CMP Rn, <operand2>\n\nCompare the value in register n with the value of operand2, for a following conditional branch."),
+    ("B", "B <label>\n\nAlways branch to the instruction at the label."),
+    ("BEQ", "BEQ <label>\n\nBranch to the label if the last comparison was equal."),
+    ("BNE", "BNE <label>\n\nBranch to the label if the last comparison was not equal."),
+    ("BGT", "BGT <label>\n\nBranch to the label if the last comparison was greater than."),
+    ("BLT", "BLT <label>\n\nBranch to the label if the last comparison was less than."),
+    ("AND", "AND Rd, Rn, <operand2>\n\nBitwise AND of the value in register n and operand2, stored in register d."),
+    ("ORR", "ORR Rd, Rn, <operand2>\n\nBitwise OR of the value in register n and operand2, stored in register d."),
+    ("EOR", "EOR Rd, Rn, <operand2>\n\nBitwise exclusive OR of the value in register n and operand2, stored in register d."),
+    ("MVN", "MVN Rd, <operand2>\n\nBitwise NOT of operand2, stored in register d."),
+    ("LSL", "LSL Rd, Rn, <operand2>\n\nShift the value in register n left by operand2 bits and store the result in register d."),
+    ("LSR", "LSR Rd, Rn, <operand2>\n\nShift the value in register n right by operand2 bits and store the result in register d."),
+    ("HALT", "HALT\n\nStop the execution of the program."),
+    ("INP", "INP Rd\n\nRead a number from the input into register d. Needs the io extension."),
+    ("OUT", "OUT Rd\n\nWrite the value in register d to the output as a number on its own line. Needs the io extension."),
+    ("OUTC", "OUTC Rd\n\nWrite the character whose code is in register d to the output. Needs the io extension."),
+    ("BL", "BL <label>\n\nStore the return address in R14 and branch to the label. Needs the stack extension."),
+    ("RET", "RET\n\nReturn to the address in R14. Needs the stack extension."),
+    ("PUSH", "PUSH Rd\n\nDecrease SP (R13) and store the value in register d at the new top of the stack. Needs the stack extension."),
+    ("POP", "POP Rd\n\nLoad the value at the top of the stack into register d and increase SP (R13). Needs the stack extension."),
+];
+
+// LSP enumerations.
+const SEVERITY_ERROR: i32 = 1;
+const SEVERITY_WARNING: i32 = 2;
+const COMPLETION_VARIABLE: i32 = 6;
+const COMPLETION_KEYWORD: i32 = 14;
+const COMPLETION_REFERENCE: i32 = 18;
+const SYMBOL_FUNCTION: i32 = 12;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+
+fn position(line: usize, character: usize) -> Json {
+    object([("line", Json::from(line)), ("character", Json::from(character))])
+}
+
+fn range(line: usize, start: usize, end: usize) -> Json {
+    object([("start", position(line, start)), ("end", position(line, end))])
+}
+
+fn is_word(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// The word under the cursor and the columns it spans.
+fn word_at(text: &str, character: usize) -> Option<(&str, usize, usize)> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut start = character.min(chars.len());
+    while start > 0 && is_word(chars[start - 1]) {
+        start -= 1;
+    }
+    let mut end = start;
+    while end < chars.len() && is_word(chars[end]) {
+        end += 1;
+    }
+    if start == end {
+        return None;
+    }
+    let byte = |index: usize| text.char_indices().nth(index).map_or(text.len(), |(byte, _)| byte);
+    Some((&text[byte(start)..byte(end)], start, end))
+}
+
+// Where `name` appears as a whole word on a line, before any comment.
+fn columns(line: &str, name: &str) -> Vec<usize> {
+    let code_end = line.find(';').unwrap_or(line.len());
+    line[..code_end]
+        .match_indices(name)
+        .filter(|(start, _)| {
+            let before = line[..*start].chars().next_back();
+            let after = line[start + name.len()..].chars().next();
+            !before.is_some_and(is_word) && !after.is_some_and(is_word)
+        })
+        .map(|(start, _)| line[..start].chars().count())
+        .collect()
+}
+
+pub struct Server {
+    documents: HashMap<String, String>,
+    dialect: Extensions,
+}
+
+impl Server {
+    pub fn new(dialect: Extensions) -> Self {
+        Server {
+            documents: HashMap::new(),
+            dialect,
+        }
+    }
+
+    fn diagnostics(&self, uri: &str) -> Json {
+        let text = self.documents.get(uri).map_or("", String::as_str);
+        let mut int = Interpreter::new(text.to_string());
+        int.extensions = self.dialect;
+        let diagnostics = lint::lint(&int)
+            .into_iter()
+            .map(|warning| {
+                let line = &int.source[warning.line];
+                let severity = if warning.code == lint::SYNTAX { SEVERITY_ERROR } else { SEVERITY_WARNING };
+                object([
+                    ("range", range(warning.line, 0, line.chars().count())),
+                    ("severity", Json::from(severity)),
+                    ("code", Json::from(warning.code)),
+                    ("source", Json::from("ian")),
+                    ("message", Json::from(warning.message)),
+                ])
+            })
+            .collect::<Vec<Json>>();
+        object([
+            ("jsonrpc", Json::from("2.0")),
+            ("method", Json::from("textDocument/publishDiagnostics")),
+            ("params", object([("uri", Json::from(uri)), ("diagnostics", Json::from(diagnostics))])),
+        ])
+    }
+
+    // The document, its lines and the word a request's position points at.
+    fn word<'a>(&'a self, params: &'a Json) -> Option<(&'a str, Vec<&'a str>, String)> {
+        let uri = params.at(&["textDocument", "uri"])?.as_str()?;
+        let text = self.documents.get(uri)?;
+        let lines: Vec<&str> = text.lines().collect();
+        let line = params.at(&["position", "line"])?.as_i64()? as usize;
+        let character = params.at(&["position", "character"])?.as_i64()? as usize;
+        let (word, _, _) = word_at(lines.get(line)?, character)?;
+        let word = word.to_string();
+        Some((uri, lines, word))
+    }
+
+    fn location(uri: &str, line: usize, start: usize, length: usize) -> Json {
+        object([("uri", Json::from(uri)), ("range", range(line, start, start + length))])
+    }
+
+    fn definition(&self, params: &Json) -> Json {
+        let Some((uri, lines, word)) = self.word(params) else {
+            return Json::Null;
+        };
+        match lines.iter().position(|line| label(line) == Some(&word)) {
+            Some(line) => Self::location(uri, line, columns(lines[line], &word)[0], word.chars().count()),
+            None => Json::Null,
+        }
+    }
+
+    fn references(&self, params: &Json) -> Json {
+        let Some((uri, lines, word)) = self.word(params) else {
+            return Json::Null;
+        };
+        if !lines.iter().any(|line| label(line) == Some(&word)) {
+            return Json::Array(vec![]);
+        }
+        let include_declaration = params.at(&["context", "includeDeclaration"]).and_then(Json::as_bool).unwrap_or(true);
+        let mut locations = vec![];
+        for (index, line) in lines.iter().enumerate() {
+            let is_declaration = label(line).is_some();
+            if is_declaration && !include_declaration {
+                continue;
+            }
+            // Only the operand of a branch refers to a label.
+            let is_branch = code(line).starts_with('B');
+            if is_declaration || is_branch {
+                for column in columns(line, &word) {
+                    locations.push(Self::location(uri, index, column, word.chars().count()));
+                }
+            }
+        }
+        Json::Array(locations)
+    }
+
+    fn hover(&self, params: &Json) -> Json {
+        let Some((_, lines, word)) = self.word(params) else {
+            return Json::Null;
+        };
+        let text = if let Some((_, description)) = MNEMONICS.iter().find(|(mnemonic, _)| *mnemonic == word) {
+            description.to_string()
+        } else if let Some(register) = word.strip_prefix('R').and_then(|number| number.parse::<usize>().ok())
+            && register < 16
+        {
+            format!("Register R{}", register)
+        } else if let Some(line) = lines.iter().position(|line| label(line) == Some(&word)) {
+            format!("Label `{}`, defined on line {}", word, line)
+        } else {
+            return Json::Null;
+        };
+        object([("contents", object([("kind", Json::from("markdown")), ("value", Json::from(text))]))])
+    }
+
+    // Mnemonics for the first word on a line; registers and labels after it.
+    fn completion(&self, params: &Json) -> Json {
+        let item = |label: String, kind: i32| object([("label", Json::from(label)), ("kind", Json::from(kind))]);
+        let uri = params.at(&["textDocument", "uri"]).and_then(Json::as_str).unwrap_or("");
+        let text = self.documents.get(uri).map_or("", String::as_str);
+        let line = params.at(&["position", "line"]).and_then(Json::as_i64).unwrap_or(0) as usize;
+        let character = params.at(&["position", "character"]).and_then(Json::as_i64).unwrap_or(0) as usize;
+        let before: String = text.lines().nth(line).unwrap_or("").chars().take(character).collect();
+
+        let mut items = vec![];
+        if before.split_whitespace().count() <= 1 && !before.ends_with(char::is_whitespace) {
+            for (mnemonic, _) in MNEMONICS {
+                items.push(item(mnemonic.to_string(), COMPLETION_KEYWORD));
+            }
+        } else {
+            for register in 0..16 {
+                items.push(item(format!("R{}", register), COMPLETION_VARIABLE));
+            }
+            for name in text.lines().filter_map(label) {
+                items.push(item(name.to_string(), COMPLETION_REFERENCE));
+            }
+        }
+        Json::Array(items)
+    }
+
+    fn symbols(&self, params: &Json) -> Json {
+        let uri = params.at(&["textDocument", "uri"]).and_then(Json::as_str).unwrap_or("");
+        let text = self.documents.get(uri).map_or("", String::as_str);
+        let mut symbols = vec![];
+        for (index, line) in text.lines().enumerate() {
+            if let Some(name) = label(line) {
+                let start = columns(line, name)[0];
+                let name_range = range(index, start, start + name.chars().count());
+                symbols.push(object([
+                    ("name", Json::from(name)),
+                    ("kind", Json::from(SYMBOL_FUNCTION)),
+                    ("range", range(index, 0, line.chars().count())),
+                    ("selectionRange", name_range),
+                ]));
+            }
+        }
+        Json::Array(symbols)
+    }
+
+    fn error(message: &Json, code: i32, text: String) -> Json {
+        let id = message.get("id").cloned().unwrap_or(Json::Null);
+        object([
+            ("jsonrpc", Json::from("2.0")),
+            ("id", id),
+            ("error", object([("code", Json::from(code)), ("message", Json::from(text))])),
+        ])
+    }
+
+    fn capabilities() -> Json {
+        object([(
+            "capabilities",
+            object([
+                ("textDocumentSync", Json::from(1)),
+                ("definitionProvider", Json::from(true)),
+                ("referencesProvider", Json::from(true)),
+                ("hoverProvider", Json::from(true)),
+                ("completionProvider", object([])),
+                ("documentSymbolProvider", Json::from(true)),
+            ]),
+        )])
+    }
+
+    // Handles one message and returns the messages to send back. Returns
+    // `None` once the client has asked the server to exit.
+    pub fn handle(&mut self, message: &Json) -> Option<Vec<Json>> {
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+        let uri = params.at(&["textDocument", "uri"]).and_then(Json::as_str).unwrap_or("").to_string();
+
+        let result = match method {
+            // A `dialect` initialization option overrides the one given on the command line.
+            "initialize" => match params.at(&["initializationOptions", "dialect"]).and_then(Json::as_str).map(Extensions::parse) {
+                Some(Err(error)) => return Some(vec![Self::error(message, INVALID_PARAMS, error)]),
+                Some(Ok(dialect)) => {
+                    self.dialect = dialect;
+                    Self::capabilities()
+                }
+                None => Self::capabilities(),
+            },
+            "shutdown" => Json::Null,
+            "exit" => return None,
+            "textDocument/didOpen" => {
+                let text = params.at(&["textDocument", "text"]).and_then(Json::as_str).unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_string());
+                return Some(vec![self.diagnostics(&uri)]);
+            }
+            // Only full-document sync is offered, so the last change is the whole text.
+            "textDocument/didChange" => {
+                let changes = params.get("contentChanges").and_then(Json::as_array).unwrap_or(&[]);
+                if let Some(text) = changes.last().and_then(|change| change.get("text")).and_then(Json::as_str) {
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+                return Some(vec![self.diagnostics(&uri)]);
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return Some(vec![self.diagnostics(&uri)]);
+            }
+            "textDocument/definition" => self.definition(&params),
+            "textDocument/references" => self.references(&params),
+            "textDocument/hover" => self.hover(&params),
+            "textDocument/completion" => self.completion(&params),
+            "textDocument/documentSymbol" => self.symbols(&params),
+            _ => {
+                // Notifications we don't handle are ignored; requests get an error.
+                if message.get("id").is_none() {
+                    return Some(vec![]);
+                }
+                return Some(vec![Self::error(message, METHOD_NOT_FOUND, format!("unsupported method '{}'", method))]);
+            }
+        };
+        let id = message.get("id").cloned().unwrap_or(Json::Null);
+        Some(vec![object([("jsonrpc", Json::from("2.0")), ("id", id), ("result", result)])])
+    }
+}
+
+// Reads one message framed with a `Content-Length` header, or `None` at the end of input.
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "message is not UTF-8"))
+}
+
+pub fn write_message<W: Write>(output: &mut W, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+// Serves one client until it exits or closes the connection. Documents are
+// checked in `dialect` unless the client asks for another when it initializes.
+pub fn run<R: BufRead, W: Write>(input: &mut R, output: &mut W, dialect: Extensions) -> io::Result<()> {
+    let mut server = Server::new(dialect);
+    while let Some(body) = read_message(input)? {
+        let Ok(message) = Json::parse(&body) else {
+            continue;
+        };
+        let Some(replies) = server.handle(&message) else {
+            break;
+        };
+        for reply in replies {
+            write_message(output, &reply)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///prog.asm";
+    const PROGRAM: &str = "MOV R0, #0\nloop:\nADD R0, R0, #1\nCMP R0, #3\nBLT loop\nHALT";
+
+    fn open(server: &mut Server, text: &str) -> Vec<Json> {
+        let open = object([
+            ("method", Json::from("textDocument/didOpen")),
+            ("params", object([("textDocument", object([("uri", Json::from(URI)), ("text", Json::from(text))]))])),
+        ]);
+        server.handle(&open).unwrap()
+    }
+
+    fn opened(text: &str) -> (Server, Vec<Json>) {
+        let mut server = Server::new(Extensions::default());
+        let replies = open(&mut server, text);
+        (server, replies)
+    }
+
+    fn request(server: &mut Server, method: &str, line: usize, character: usize) -> Json {
+        let message = object([
+            ("id", Json::from(7)),
+            ("method", Json::from(method)),
+            (
+                "params",
+                object([
+                    ("textDocument", object([("uri", Json::from(URI))])),
+                    ("position", position(line, character)),
+                ]),
+            ),
+        ]);
+        let replies = server.handle(&message).unwrap();
+        assert_eq!(replies[0].get("id"), Some(&Json::from(7)));
+        replies[0].get("result").cloned().unwrap()
+    }
+
+    #[test]
+    fn diagnostics() {
+        let (_, replies) = opened("MOV R0, 1\nHALT");
+        let diagnostics = replies[0].at(&["params", "diagnostics"]).and_then(Json::as_array).unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].get("severity"), Some(&Json::from(SEVERITY_ERROR)));
+        assert_eq!(diagnostics[0].get("message").and_then(Json::as_str), Some("syntax error: invalid operand '1'"));
+        assert_eq!(diagnostics[0].get("range"), Some(&range(0, 0, 9)));
+    }
+
+    #[test]
+    fn dialect() {
+        let source = "INP R0\nHALT";
+        let (_, replies) = opened(source);
+        assert_eq!(replies[0].at(&["params", "diagnostics"]).and_then(Json::as_array).unwrap().len(), 1);
+
+        let mut server = Server::new(Extensions::default());
+        let initialize = |dialect: &str| {
+            object([
+                ("id", Json::from(1)),
+                ("method", Json::from("initialize")),
+                ("params", object([("initializationOptions", object([("dialect", Json::from(dialect))]))])),
+            ])
+        };
+        let replies = server.handle(&initialize("nope")).unwrap();
+        assert_eq!(replies[0].at(&["error", "code"]), Some(&Json::from(INVALID_PARAMS)));
+        server.handle(&initialize("aqa+io")).unwrap();
+        let replies = open(&mut server, source);
+        assert_eq!(replies[0].at(&["params", "diagnostics"]), Some(&Json::Array(vec![])));
+    }
+
+    #[test]
+    fn definition_and_references() {
+        let (mut server, _) = opened(PROGRAM);
+        assert_eq!(request(&mut server, "textDocument/definition", 4, 6).to_string(), Server::location(URI, 1, 0, 4).to_string());
+        let references = request(&mut server, "textDocument/references", 1, 2);
+        assert_eq!(references, Json::Array(vec![Server::location(URI, 1, 0, 4), Server::location(URI, 4, 4, 4)]));
+        assert_eq!(request(&mut server, "textDocument/definition", 0, 1), Json::Null);
+    }
+
+    #[test]
+    fn hover() {
+        let (mut server, _) = opened(PROGRAM);
+        let hover = request(&mut server, "textDocument/hover", 3, 1);
+        assert!(hover.at(&["contents", "value"]).and_then(Json::as_str).unwrap().starts_with("CMP Rn, <operand2>"));
+        let hover = request(&mut server, "textDocument/hover", 2, 5);
+        assert_eq!(hover.at(&["contents", "value"]).and_then(Json::as_str), Some("Register R0"));
+    }
+
+    #[test]
+    fn completion_and_symbols() {
+        let (mut server, _) = opened(PROGRAM);
+        let items = request(&mut server, "textDocument/completion", 3, 1);
+        assert_eq!(items.as_array().unwrap().len(), 25);
+        let items = request(&mut server, "textDocument/completion", 4, 4);
+        assert_eq!(items.as_array().unwrap().last().and_then(|item| item.get("label")), Some(&Json::from("loop")));
+        let symbols = request(&mut server, "textDocument/documentSymbol", 0, 0);
+        assert_eq!(symbols.as_array().unwrap()[0].get("name"), Some(&Json::from("loop")));
+    }
+
+    #[test]
+    fn framing() {
+        let mut input = Vec::new();
+        write_message(&mut input, &object([("id", Json::from(1)), ("method", Json::from("initialize"))])).unwrap();
+        write_message(&mut input, &object([("id", Json::from(2)), ("method", Json::from("nope"))])).unwrap();
+        write_message(&mut input, &object([("method", Json::from("exit"))])).unwrap();
+        let mut output = Vec::new();
+        run(&mut io::Cursor::new(input), &mut output, Extensions::default()).unwrap();
+
+        let mut output = io::Cursor::new(output);
+        let first = Json::parse(&read_message(&mut output).unwrap().unwrap()).unwrap();
+        assert_eq!(first.at(&["result", "capabilities", "hoverProvider"]), Some(&Json::from(true)));
+        let second = Json::parse(&read_message(&mut output).unwrap().unwrap()).unwrap();
+        assert_eq!(second.at(&["error", "code"]), Some(&Json::from(METHOD_NOT_FOUND)));
+        assert_eq!(read_message(&mut output).unwrap(), None);
+    }
+}
@@ -1,10 +1,57 @@
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::fs;
 use std::io;
+use std::io::Read;
 use std::io::Write;
-
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+
+use console::Console;
+use console::Input;
+use cycles::CostModel;
+use device::Devices;
+use encoding::Image;
+use encoding::decode_word;
+use instruction::Condition;
+use instruction::Extensions;
+use instruction::Instruction;
+use instruction::LR;
+use instruction::MemoryRef;
+use instruction::Operand;
+use instruction::SP;
+use instruction::decode;
+use instruction::label;
+
+mod compile;
+mod console;
+mod coverage;
+mod cycles;
+mod dap;
+mod decompile;
+mod device;
+mod encoding;
+mod flow;
+mod format;
+mod gdb;
+mod grade;
+mod graph;
+mod instruction;
+mod json;
+mod lint;
+mod lsp;
+mod object;
+mod profile;
+mod script;
+mod serve;
+mod testcase;
+mod trace;
+mod tui;
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Debug)]
 enum Compare {
     EQ,
@@ -13,6 +60,103 @@ enum Compare {
     NONE,
 }
 
+#[derive(PartialEq, Debug)]
+enum Trap {
+    Syntax(String),
+    Address(String),
+    Label(String),
+    Instruction(i32),
+    Input(String),
+    StackOverflow,
+    StackUnderflow,
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trap::Syntax(message) => write!(f, "syntax error: {}", message),
+            Trap::Address(address) => write!(f, "memory address '{}' out of range", address),
+            Trap::Label(label) => write!(f, "undefined label '{}'", label),
+            Trap::Instruction(word) => write!(f, "invalid instruction word {:#010x}", *word as u32),
+            Trap::Input(message) => write!(f, "input error: {}", message),
+            Trap::StackOverflow => write!(f, "stack overflow: PUSH with the stack full"),
+            Trap::StackUnderflow => write!(f, "stack underflow: POP with the stack empty"),
+        }
+    }
+}
+
+// Execution counts used for coverage and profiling. Unlike the machine state
+// these survive `reset`, so they accumulate across every run of the same program.
+struct Stats {
+    line_hits: Vec<u64>,
+    branches: Vec<(u64, u64)>,
+    mem_reads: u64,
+    mem_writes: u64,
+    cycles: u64,
+}
+
+impl Stats {
+    fn new(lines: usize) -> Self {
+        Self {
+            line_hits: vec![0; lines],
+            branches: vec![(0, 0); lines],
+            mem_reads: 0,
+            mem_writes: 0,
+            cycles: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Phase {
+    Fetch,
+    Decode,
+    Execute,
+}
+
+// What the MDR or CIR holds: an instruction is a line of source unless the
+// program has been assembled into memory, and data is always a word.
+#[derive(Clone, PartialEq, Debug)]
+enum Word {
+    Text(String),
+    Value(i32),
+}
+
+impl fmt::Display for Word {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Word::Text(text) => write!(f, "{}", text),
+            Word::Value(value) => write!(f, "{} ({:#010x})", value, *value as u32),
+        }
+    }
+}
+
+// The registers the AQA specification uses to describe the fetch-decode-execute
+// cycle. The PC is `line_num`.
+struct Fde {
+    phase: Phase,
+    fetched: usize,
+    mar: usize,
+    mdr: Word,
+    cir: Word,
+    acc: i32,
+    instruction: Option<Instruction>,
+}
+
+impl Fde {
+    fn new() -> Self {
+        Self {
+            phase: Phase::Fetch,
+            fetched: 0,
+            mar: 0,
+            mdr: Word::Value(0),
+            cir: Word::Value(0),
+            acc: 0,
+            instruction: None,
+        }
+    }
+}
+
 struct Interpreter {
     reg: [i32; 16],
     mem: [i32; 256],
@@ -21,8 +165,23 @@ struct Interpreter {
     cmp: Compare,
     source: Vec<String>,
     jump_map: HashMap<String, usize>,
+    fde: Fde,
+    image: Option<Image>,
+    stats: Stats,
+    costs: CostModel,
+    extensions: Extensions,
+    console: Console,
+    devices: Devices,
+    // The return address of each call still running, innermost last. It only
+    // feeds the debugger's call stack; RET itself uses R14.
+    calls: Vec<usize>,
 }
 
+// The stack extension's stack grows down from just below the built-in devices
+// at 240, so pushes never reach them.
+const STACK_TOP: usize = 240;
+const STACK_SIZE: usize = 32;
+
 impl Interpreter {
     fn new(source_string: String) -> Self {
         let mut source_vec: Vec<String> = vec![];
@@ -33,8 +192,7 @@ impl Interpreter {
         let jump_hashmap: HashMap<String, usize> = source_vec
             .iter()
             .enumerate()
-            .filter(|(_, line)| line.ends_with(':'))
-            .map(|(index, line)| (line.strip_suffix(':').unwrap().to_string(), index + 1))
+            .filter_map(|(index, line)| label(line).map(|label| (label.to_string(), index + 1)))
             .collect();
 
         Self {
@@ -43,6 +201,14 @@ impl Interpreter {
             line_num: 0,
             running: true,
             cmp: Compare::NONE,
+            fde: Fde::new(),
+            image: None,
+            stats: Stats::new(source_vec.len()),
+            costs: CostModel::default(),
+            extensions: Extensions::default(),
+            console: Console::new(),
+            devices: Devices::default(),
+            calls: vec![],
             source: source_vec,
             jump_map: jump_hashmap,
         }
@@ -54,450 +220,316 @@ impl Interpreter {
         self.line_num = 0;
         self.running = true;
         self.cmp = Compare::NONE;
+        self.fde = Fde::new();
+        self.console.rewind();
+        self.devices.reset();
+        self.calls.clear();
+        if self.extensions.stack {
+            self.reg[SP] = STACK_TOP as i32;
+        }
+        if let Some(image) = &self.image {
+            self.mem[..image.words.len()].copy_from_slice(&image.words);
+        }
     }
 
-    fn tick(&mut self) {
-        let line = &self.source[self.line_num];
-        if line.ends_with(':') {
-            self.line_num += 1;
-            return;
+    // Switches to von Neumann mode: the program is assembled into words at the
+    // bottom of `mem` and the PC indexes memory instead of source lines.
+    fn load_into_memory(&mut self) -> Result<(), (usize, Trap)> {
+        let image = encoding::assemble(&self.source, self.extensions)?;
+        self.image = Some(image);
+        self.reset();
+        Ok(())
+    }
+
+    // The source line an address was assembled from, or for programs run from
+    // source, the line itself.
+    fn source_line(&self, address: usize) -> Option<usize> {
+        match &self.image {
+            Some(image) => image.lines.get(address).copied(),
+            None => (address < self.source.len()).then_some(address),
         }
-        let mut line = line.split_whitespace();
-        match line.next() {
-            Some("LDR") => {
-                let d = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-                let mem_ref = line.next().unwrap().parse::<usize>().unwrap();
-                self.reg[d] = self.mem[mem_ref];
-            }
-
-            Some("STR") => {
-                let d = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-                let mem_ref = line.next().unwrap().parse::<usize>().unwrap();
-                self.mem[mem_ref] = self.reg[d];
-            }
-
-            Some("ADD") => {
-                let d = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let n = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let operand2 = line.next().unwrap();
-
-                if operand2.starts_with('#') {
-                    self.reg[d] = self.reg[n]
-                        + operand2
-                            .strip_prefix('#')
-                            .expect("hashtag")
-                            .parse::<i32>()
-                            .unwrap();
-                } else if operand2.starts_with('R') {
-                    self.reg[d] = self.reg[n]
-                        + self.reg[operand2
-                            .strip_prefix("R")
-                            .expect("R")
-                            .parse::<usize>()
-                            .unwrap()];
-                } else {
-                    panic!("operand2 error");
-                }
-            }
+    }
 
-            Some("SUB") => {
-                let d = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let n = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let operand2 = line.next().unwrap();
-
-                if operand2.starts_with('#') {
-                    self.reg[d] = self.reg[n]
-                        - operand2
-                            .strip_prefix('#')
-                            .expect("hashtag")
-                            .parse::<i32>()
-                            .unwrap();
-                } else if operand2.starts_with('R') {
-                    self.reg[d] = self.reg[n]
-                        - self.reg[operand2
-                            .strip_prefix("R")
-                            .expect("R")
-                            .parse::<usize>()
-                            .unwrap()];
-                } else {
-                    panic!("operand2 error");
-                }
-            }
+    // The line each running call will return to, innermost first.
+    fn call_stack(&self) -> Vec<usize> {
+        self.calls
+            .iter()
+            .rev()
+            .map(|&address| self.source_line(address).unwrap_or(address))
+            .collect()
+    }
 
-            Some("MOV") => {
-                let d = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let operand2 = line.next().unwrap();
-
-                if operand2.starts_with('#') {
-                    self.reg[d] = operand2
-                        .strip_prefix('#')
-                        .expect("hashtag")
-                        .parse::<i32>()
-                        .unwrap();
-                } else if operand2.starts_with('R') {
-                    self.reg[d] = self.reg[operand2
-                        .strip_prefix('R')
-                        .expect("R")
-                        .parse::<usize>()
-                        .unwrap()];
-                } else {
-                    panic!("operand2 error");
-                }
-            }
+    // Finds every line that would trap when executed, without running the program.
+    fn check(&self) -> Vec<(usize, Trap)> {
+        self.source
+            .iter()
+            .enumerate()
+            .filter_map(|(line, text)| {
+                decode(text, &self.jump_map, self.extensions).err().map(|trap| (line, trap))
+            })
+            .collect()
+    }
 
-            Some("CMP") => {
-                let n = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let operand2 = line.next().unwrap();
-
-                if operand2.starts_with('#') {
-                    let value: i32 = operand2
-                        .strip_prefix('#')
-                        .expect("hashtag")
-                        .parse()
-                        .unwrap();
-                    match self.reg[n].cmp(&value) {
-                        Ordering::Less => self.cmp = Compare::LT,
-                        Ordering::Greater => self.cmp = Compare::GT,
-                        Ordering::Equal => self.cmp = Compare::EQ,
-                    }
-                } else if operand2.starts_with('R') {
-                    let value: i32 = self.reg[operand2
-                        .strip_prefix('R')
-                        .expect("R")
-                        .parse::<usize>()
-                        .unwrap()];
-                    match self.reg[n].cmp(&value) {
-                        Ordering::Less => self.cmp = Compare::LT,
-                        Ordering::Greater => self.cmp = Compare::GT,
-                        Ordering::Equal => self.cmp = Compare::EQ,
-                    }
-                } else {
-                    panic!("operand2 error");
-                }
-            }
+    fn value(&self, operand: Operand) -> i32 {
+        match operand {
+            Operand::Immediate(value) => value,
+            Operand::Register(register) => self.reg[register],
+        }
+    }
 
-            Some("B") => {
-                let label = line
-                    .next()
-                    .expect("Missing label")
-                    .to_string();
-                self.line_num = *self.jump_map.get(&label).unwrap();
-                return;
-            }
+    // Results from the ALU pass through ACC on their way to the destination register.
+    fn alu(&mut self, d: usize, result: i32) {
+        self.fde.acc = result;
+        self.reg[d] = result;
+    }
 
-            Some("BEQ") => {
-                let label = line
-                    .next()
-                    .expect("Missing label")
-                    .to_string();
+    // MAR <- PC, MDR <- [MAR], PC <- PC + 1, CIR <- MDR
+    fn fetch(&mut self) {
+        let end = if self.image.is_some() { self.mem.len() } else { self.source.len() };
+        if self.line_num >= end {
+            // Nothing was fetched, so nothing is left to execute.
+            self.running = false;
+            self.fde.instruction = None;
+            return;
+        }
+        self.fde.fetched = self.line_num;
+        self.fde.mar = self.line_num;
+        self.fde.mdr = if self.image.is_some() {
+            Word::Value(self.mem[self.line_num])
+        } else {
+            Word::Text(self.source[self.line_num].clone())
+        };
+        self.line_num += 1;
+        self.fde.cir = self.fde.mdr.clone();
+        self.fde.phase = Phase::Decode;
+    }
 
-                if let Compare::EQ = self.cmp {
-                    self.line_num = *self.jump_map.get(&label).unwrap();
-                }
-                return;
+    fn decode(&mut self) -> Result<(), Trap> {
+        self.fde.instruction = match &self.fde.cir {
+            Word::Text(line) => decode(line, &self.jump_map, self.extensions)?,
+            Word::Value(word) => {
+                // Words in memory never went through the parser, and a program
+                // can write any word there, so the dialect is checked here.
+                let instruction = decode_word(*word)?;
+                self.extensions.allow(&instruction)?;
+                Some(instruction)
             }
-
-            Some("BNE") => {
-                let label = line
-                    .next()
-                    .expect("Missing label")
-                    .to_string();
-
-                if let Compare::GT = self.cmp {
-                    self.line_num = *self.jump_map.get(&label).unwrap();
-                } else if let Compare::LT = self.cmp {
-                    self.line_num = *self.jump_map.get(&label).unwrap();
-                }
-                return;
+        };
+        if let Some(instruction) = self.fde.instruction {
+            if let Some(line) = self.source_line(self.fde.fetched) {
+                self.stats.line_hits[line] += 1;
             }
+            self.stats.cycles += self.costs.cost(instruction.mnemonic());
+        }
+        self.fde.phase = Phase::Execute;
+        Ok(())
+    }
 
-            Some("BGT") => {
-                let label = line
-                    .next()
-                    .expect("Missing label")
-                    .to_string();
-
-                if let Compare::GT = self.cmp {
-                    self.line_num = *self.jump_map.get(&label).unwrap();
+    fn execute(&mut self) -> Result<(), Trap> {
+        self.fde.phase = Phase::Fetch;
+        let Some(instruction) = self.fde.instruction else {
+            return Ok(());
+        };
+        match instruction {
+            Instruction::Ldr(d, memory) => {
+                let address = self.address(memory)?;
+                self.fde.mar = address;
+                let value = self.load(address)?;
+                self.fde.mdr = Word::Value(value);
+                self.reg[d] = value;
+                self.stats.mem_reads += 1;
+            }
+            Instruction::Str(d, memory) => {
+                let address = self.address(memory)?;
+                self.fde.mar = address;
+                self.fde.mdr = Word::Value(self.reg[d]);
+                self.store(address, self.reg[d])?;
+                self.stats.mem_writes += 1;
+            }
+            Instruction::Add(d, n, operand) => self.alu(d, self.reg[n].wrapping_add(self.value(operand))),
+            Instruction::Sub(d, n, operand) => self.alu(d, self.reg[n].wrapping_sub(self.value(operand))),
+            Instruction::Mov(d, operand) => self.alu(d, self.value(operand)),
+            Instruction::Cmp(n, operand) => {
+                let value = self.value(operand);
+                self.fde.acc = self.reg[n].wrapping_sub(value);
+                self.cmp = match self.reg[n].cmp(&value) {
+                    Ordering::Less => Compare::LT,
+                    Ordering::Greater => Compare::GT,
+                    Ordering::Equal => Compare::EQ,
+                };
+            }
+            Instruction::Branch(condition, target) => {
+                let taken = match condition {
+                    Condition::Always => true,
+                    Condition::EQ => self.cmp == Compare::EQ,
+                    Condition::NE => self.cmp == Compare::GT || self.cmp == Compare::LT,
+                    Condition::GT => self.cmp == Compare::GT,
+                    Condition::LT => self.cmp == Compare::LT,
+                };
+                if condition == Condition::Always {
+                    self.line_num = target;
+                } else {
+                    self.branch(target, taken);
                 }
-                return;
             }
-
-            Some("BLT") => {
-                let label = line
-                    .next()
-                    .expect("Missing label")
-                    .to_string();
-
-                if let Compare::LT = self.cmp {
-                    self.line_num = *self.jump_map.get(&label).unwrap();
+            Instruction::And(d, n, operand) => self.alu(d, self.reg[n] & self.value(operand)),
+            Instruction::Orr(d, n, operand) => self.alu(d, self.reg[n] | self.value(operand)),
+            Instruction::Eor(d, n, operand) => self.alu(d, self.reg[n] ^ self.value(operand)),
+            Instruction::Mvn(d, operand) => self.alu(d, !self.value(operand)),
+            Instruction::Lsl(d, n, operand) => self.alu(d, self.reg[n].wrapping_shl(self.value(operand) as u32)),
+            Instruction::Lsr(d, n, operand) => self.alu(d, self.reg[n].wrapping_shr(self.value(operand) as u32)),
+            Instruction::Halt => self.running = false,
+            Instruction::Inp(d) => self.reg[d] = self.console.read()?,
+            Instruction::Out(d) => self.console.write(&format!("{}\n", self.reg[d])),
+            Instruction::Outc(d) => self.console.write_char(self.reg[d]),
+            Instruction::Bl(target) => {
+                self.reg[LR] = self.line_num as i32;
+                self.calls.push(self.line_num);
+                self.line_num = target;
+            }
+            Instruction::Ret => {
+                let target = usize::try_from(self.reg[LR]).map_err(|_| Trap::Address(self.reg[LR].to_string()))?;
+                // Returning past several calls at once drops them all.
+                if let Some(index) = self.calls.iter().rposition(|&call| call == target) {
+                    self.calls.truncate(index);
                 }
-                return;
-            }
-
-            Some("AND") => {
-                let d = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let n = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let operand2 = line.next().unwrap();
-
-                if operand2.starts_with('#') {
-                    self.reg[d] = self.reg[n] & operand2.strip_prefix('#').expect("hashtag").parse::<i32>().unwrap();
-                } else if operand2.starts_with('R') {
-                    self.reg[d] = self.reg[n] & self.reg[operand2.strip_prefix('R').expect("R").parse::<usize>().unwrap()];
-                } else {
-                    panic!("operand2 error");
+                self.line_num = target;
+            }
+            Instruction::Push(d) => {
+                let address = self.stack_pointer()?;
+                if address <= STACK_TOP - STACK_SIZE {
+                    return Err(Trap::StackOverflow);
                 }
+                self.stack_word(address - 1)?;
+                self.fde.mar = address - 1;
+                self.fde.mdr = Word::Value(self.reg[d]);
+                self.mem[address - 1] = self.reg[d];
+                self.reg[SP] -= 1;
+                self.stats.mem_writes += 1;
             }
-
-            Some("ORR") => {
-                let d = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let n = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let operand2 = line.next().unwrap();
-
-                if operand2.starts_with('#') {
-                    self.reg[d] = self.reg[n] | operand2.strip_prefix('#').expect("hashtag").parse::<i32>().unwrap();
-                } else if operand2.starts_with('R') {
-                    self.reg[d] = self.reg[n] | self.reg[operand2.strip_prefix('R').expect("R").parse::<usize>().unwrap()];
-                } else {
-                    panic!("operand2 error");
+            Instruction::Pop(d) => {
+                let address = self.stack_pointer()?;
+                if address == STACK_TOP {
+                    return Err(Trap::StackUnderflow);
                 }
+                self.stack_word(address)?;
+                self.fde.mar = address;
+                self.fde.mdr = Word::Value(self.mem[address]);
+                self.reg[SP] += 1;
+                self.reg[d] = self.mem[address];
+                self.stats.mem_reads += 1;
             }
+        }
+        self.devices.tick();
+        Ok(())
+    }
 
-            Some("EOR") => {
-                let d = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let n = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let operand2 = line.next().unwrap();
-
-                if operand2.starts_with('#') {
-                    self.reg[d] = self.reg[n] ^ operand2.strip_prefix('#').expect("hashtag").parse::<i32>().unwrap();
-                } else if operand2.starts_with('R') {
-                    self.reg[d] = self.reg[n] ^ self.reg[operand2.strip_prefix('R').expect("R").parse::<usize>().unwrap()];
-                } else {
-                    panic!("operand2 error");
-                }
+    // The address a memory reference points to. Indexed addresses are only
+    // known at run time, so they are checked against `mem` here.
+    fn address(&self, memory: MemoryRef) -> Result<usize, Trap> {
+        match memory {
+            MemoryRef::Direct(address) => Ok(address),
+            MemoryRef::Indexed(n, offset) => {
+                let address = self.reg[n].wrapping_add(offset);
+                usize::try_from(address)
+                    .ok()
+                    .filter(|&address| address < self.mem.len())
+                    .ok_or_else(|| Trap::Address(address.to_string()))
             }
+        }
+    }
 
-            Some("MVN") => {
-                let d = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
+    // SP as an address, which must lie within the stack.
+    fn stack_pointer(&self) -> Result<usize, Trap> {
+        usize::try_from(self.reg[SP])
+            .ok()
+            .filter(|address| (STACK_TOP - STACK_SIZE..=STACK_TOP).contains(address))
+            .ok_or_else(|| Trap::Address(self.reg[SP].to_string()))
+    }
 
-                let operand2 = line.next().unwrap();
+    // The stack lives in `mem`, so a device mapped over part of it traps.
+    fn stack_word(&self, address: usize) -> Result<(), Trap> {
+        match self.devices.name(address) {
+            Some(_) => Err(Trap::Address(address.to_string())),
+            None => Ok(()),
+        }
+    }
 
-                if operand2.starts_with('#') {
-                    self.reg[d] = ! operand2.strip_prefix('#').expect("hashtag").parse::<i32>().unwrap();
-                } else if operand2.starts_with('R') {
-                    self.reg[d] = ! self.reg[operand2.strip_prefix('R').expect("R").parse::<usize>().unwrap()];
-                } else {
-                    panic!("operand2 error");
-                }
+    // Reads a word from memory, or from the device mapped to the address.
+    fn load(&mut self, address: usize) -> Result<i32, Trap> {
+        match self.devices.get(address) {
+            Some((device, offset)) => device.read(offset, &mut self.console),
+            None => Ok(self.mem[address]),
+        }
+    }
+
+    fn store(&mut self, address: usize, value: i32) -> Result<(), Trap> {
+        match self.devices.get(address) {
+            Some((device, offset)) => device.write(offset, value, &mut self.console),
+            None => {
+                self.mem[address] = value;
+                Ok(())
             }
+        }
+    }
 
-            Some("LSL") => {
-                let d = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let n = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let operand2 = line.next().unwrap();
-
-                if operand2.starts_with('#') {
-                    self.reg[d] = self.reg[n] << operand2.strip_prefix('#').expect("hashtag").parse::<i32>().unwrap();
-                } else if operand2.starts_with('R') {
-                    self.reg[d] = self.reg[n] << self.reg[operand2.strip_prefix('R').expect("R").parse::<usize>().unwrap()];
-                } else {
-                    panic!("operand2 error");
-                }
+    // Records which way a conditional branch went, jumping if it was taken.
+    fn branch(&mut self, target: usize, taken: bool) {
+        if let Some(line) = self.source_line(self.fde.fetched) {
+            let (taken_count, not_taken_count) = &mut self.stats.branches[line];
+            if taken {
+                *taken_count += 1;
+            } else {
+                *not_taken_count += 1;
             }
+        }
+        if taken {
+            self.line_num = target;
+        }
+    }
 
-            Some("LSR") => {
-                let d = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let n = line
-                    .next()
-                    .unwrap()
-                    .strip_prefix('R')
-                    .expect("Invalid syntax")
-                    .strip_suffix(',')
-                    .expect("Invalid syntax")
-                    .parse::<usize>()
-                    .unwrap();
-
-                let operand2 = line.next().unwrap();
-
-                if operand2.starts_with('#') {
-                    self.reg[d] = self.reg[n] >> operand2.strip_prefix('#').expect("hashtag").parse::<i32>().unwrap();
-                } else if operand2.starts_with('R') {
-                    self.reg[d] = self.reg[n] >> self.reg[operand2.strip_prefix('R').expect("R").parse::<usize>().unwrap()];
-                } else {
-                    panic!("operand2 error");
-                }
+    // Runs the next phase of the fetch-decode-execute cycle. A trap rewinds the
+    // PC to the instruction that caused it and starts the cycle again.
+    fn micro_tick(&mut self) -> Result<(), Trap> {
+        let result = match self.fde.phase {
+            Phase::Fetch => {
+                self.fetch();
+                Ok(())
             }
+            Phase::Decode => self.decode(),
+            Phase::Execute => self.execute(),
+        };
+        if result.is_err() {
+            self.line_num = self.fde.fetched;
+            self.fde.phase = Phase::Fetch;
+        }
+        result
+    }
 
-            Some("HALT") => {
-                self.running = false;
+    // Runs a whole instruction, or the rest of one that is part way through a micro-step.
+    fn tick(&mut self) -> Result<(), Trap> {
+        loop {
+            self.micro_tick()?;
+            if self.fde.phase == Phase::Fetch || !self.running {
+                return Ok(());
             }
-            Some(&_) => (),
-            None => (),
         }
-        self.line_num += 1;
+    }
+
+    fn fde_summary(&self) -> String {
+        format!(
+            "Next phase: {:?}\nPC = {}  MAR = {}  ACC = {}\nMDR = {}\nCIR = {}",
+            self.fde.phase, self.line_num, self.fde.mar, self.fde.acc, self.fde.mdr, self.fde.cir
+        )
     }
 
     fn menu(&mut self, program_running: &mut bool) {
-        println!("Current line number = {}", self.line_num);
+        println!("Dialect: {}", self.extensions);
+        if self.image.is_some() {
+            println!("Current address (PC) = {}", self.line_num);
+        } else {
+            println!("Current line number = {}", self.line_num);
+        }
         println!("1. Tick one line");
         println!("2. Run full program");
         println!("3. Display contents of register");
@@ -506,6 +538,8 @@ impl Interpreter {
         println!("6. Set memory value at address");
         println!("7. Reset interpreter");
         println!("8. Quit");
+        println!("9. Micro-step (fetch, decode, execute)");
+        println!("10. Display call stack");
         print!("Select: ");
         io::stdout().flush().unwrap();
 
@@ -514,14 +548,19 @@ impl Interpreter {
         stdin.read_line(&mut selection).unwrap();
         match selection.trim() {
             "1" => {
-                self.tick();
-                println!("One line ticked.");
+                match self.tick() {
+                    Ok(()) => println!("One line ticked."),
+                    Err(trap) => println!("Trap at line {}: {}", self.line_num, trap),
+                }
                 println!();
             }
 
             "2" => {
                 while self.running {
-                    self.tick();
+                    if let Err(trap) = self.tick() {
+                        println!("Trap at line {}: {}", self.line_num, trap);
+                        break;
+                    }
                 }
                 println!("Full program ran.");
                 println!();
@@ -543,7 +582,10 @@ impl Interpreter {
                 let mut mem_address = String::new();
                 stdin.read_line(&mut mem_address).unwrap();
                 let mem_address: usize = mem_address.trim().parse().unwrap();
-                println!("Contents of memory at {} = {}", mem_address, self.mem[mem_address]);
+                match self.devices.name(mem_address) {
+                    Some(device) => println!("Memory at {} is mapped to the {} device", mem_address, device),
+                    None => println!("Contents of memory at {} = {}", mem_address, self.mem[mem_address]),
+                }
                 println!();
             }
 
@@ -590,20 +632,418 @@ impl Interpreter {
                 *program_running = false;
             }
 
+            "9" => {
+                if let Err(trap) = self.micro_tick() {
+                    println!("Trap at line {}: {}", self.line_num, trap);
+                }
+                println!("{}", self.fde_summary());
+                println!();
+            }
+
+            "10" => {
+                let calls = self.call_stack();
+                if calls.is_empty() {
+                    println!("No calls running");
+                }
+                for (depth, line) in calls.iter().enumerate() {
+                    println!("{}. returns to line {}", depth + 1, line);
+                }
+                println!();
+            }
+
             _ => ()
         }
     }
 }
 
 
+// Flags that stand alone rather than taking a value.
+const SWITCHES: [&str; 3] = ["--von-neumann", "--check", "--counts"];
+
+// Splits `--name value` pairs and switches from positional arguments.
+// Switches are recorded with an empty value.
+fn split_flags(args: &[String]) -> (Vec<String>, HashMap<String, String>) {
+    let mut positional = vec![];
+    let mut flags = HashMap::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if SWITCHES.contains(&arg.as_str()) {
+            flags.insert(arg.clone(), String::new());
+        } else if arg.starts_with("--") {
+            let value = args.next().expect("Missing value after flag");
+            flags.insert(arg.clone(), value.clone());
+        } else {
+            positional.push(arg.clone());
+        }
+    }
+    (positional, flags)
+}
+
+// Reads a program and applies the flags that choose how it is loaded and
+// where `INP` reads from. Object files from `assemble` are disassembled first,
+// so exercises can be shipped as images and still be stepped through line by
+// line.
+fn load(file_path: &str, flags: &HashMap<String, String>) -> Interpreter {
+    let source = if file_path.ends_with(".hex") || file_path.ends_with(".bin") {
+        let bytes = fs::read(file_path).expect("File read error");
+        object::read(&bytes)
+            .and_then(|words| object::disassemble(&words))
+            .unwrap_or_else(|error| {
+                eprintln!("{}", error);
+                process::exit(2);
+            })
+    } else {
+        fs::read_to_string(file_path).expect("File read error")
+    };
+    let mut int = Interpreter::new(source);
+    int.extensions = dialect(flags);
+    // The stack extension starts SP at the top of the stack.
+    int.reset();
+    let values = match (flags.get("--input"), flags.get("--input-file")) {
+        (Some(values), _) => Some(values.clone()),
+        (None, Some(input_path)) => Some(fs::read_to_string(input_path).expect("File read error")),
+        (None, None) => None,
+    };
+    let input = match values.map(|values| console::parse_values(&values)) {
+        Some(Ok(values)) => Input::Values(values),
+        Some(Err(error)) => {
+            eprintln!("{}", error);
+            process::exit(2);
+        }
+        None => Input::Stdin,
+    };
+    int.console.set_input(input);
+    if let Some(devices) = flags.get("--devices") {
+        int.devices = Devices::parse(devices).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            process::exit(2);
+        });
+    }
+    if flags.contains_key("--von-neumann")
+        && let Err((line, trap)) = int.load_into_memory()
+    {
+        eprintln!("line {}: {}", line, trap);
+        process::exit(2);
+    }
+    int
+}
+
+// The dialect named by `--dialect`, with any extensions from `--extensions`
+// added. Strict AQA if neither is given.
+fn dialect(flags: &HashMap<String, String>) -> Extensions {
+    let names: Vec<&str> = ["--dialect", "--extensions"]
+        .iter()
+        .filter_map(|flag| flags.get(*flag))
+        .map(String::as_str)
+        .collect();
+    Extensions::parse(&names.join(",")).unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        process::exit(2);
+    })
+}
+
+// Whether `--format` asks for JSON rather than the default text output.
+fn json_format(flags: &HashMap<String, String>) -> bool {
+    match flags.get("--format").map(String::as_str) {
+        None | Some("text") => false,
+        Some("json") => true,
+        Some(format) => {
+            eprintln!("unknown format '{}', expected 'text' or 'json'", format);
+            process::exit(2);
+        }
+    }
+}
+
+// Runs every case in the spec, or the program once from a clear state, so the
+// interpreter's stats can be reported.
+fn run_cases(file_path: &str, spec_path: Option<&String>, flags: &HashMap<String, String>, costs: CostModel) -> Interpreter {
+    let source = fs::read_to_string(file_path).expect("File read error");
+    let spec = match spec_path {
+        Some(spec_path) => fs::read_to_string(spec_path).expect("File read error"),
+        None => String::from("case run"),
+    };
+    let cases = testcase::parse(&spec, dialect(flags)).unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        process::exit(2);
+    });
+    let mut int = Interpreter::new(source);
+    int.costs = costs;
+    for case in &cases {
+        testcase::run_case(&mut int, case);
+    }
+    int
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let file_path = &args[1];
 
-    let source = fs::read_to_string(file_path).expect("File read error");
+    if args[1] == "tui" {
+        let (positional, flags) = split_flags(&args[2..]);
+        tui::run(load(&positional[0], &flags), &positional[0]);
+        return;
+    }
+
+    if args[1] == "script" {
+        let (positional, flags) = split_flags(&args[2..]);
+        let mut int = load(&positional[0], &flags);
+        let commands = match positional.get(1).map(String::as_str) {
+            Some("-") | None => {
+                let mut commands = String::new();
+                io::stdin().read_to_string(&mut commands).expect("stdin read error");
+                commands
+            }
+            Some(script_path) => fs::read_to_string(script_path).expect("File read error"),
+        };
+        match script::run(&mut int, &commands, &mut io::stdout()) {
+            Ok(true) => return,
+            Ok(false) => process::exit(1),
+            Err(error) => {
+                eprintln!("{}", error);
+                process::exit(2);
+            }
+        }
+    }
+
+    if args[1] == "run" || args[1] == "trace" {
+        let (positional, flags) = split_flags(&args[2..]);
+        let mut int = load(&positional[0], &flags);
+        let limit = flags.get("--limit").map_or(testcase::DEFAULT_LIMIT, |limit| limit.parse().expect("Invalid step limit"));
+        int.console.echo = !json_format(&flags);
+        let run = trace::run(&mut int, limit, args[1] == "trace");
+        if json_format(&flags) {
+            println!("{}", trace::json(&int, &run));
+        } else {
+            print!("{}", trace::report(&int, &run));
+        }
+        if run.trap.is_some() || int.running {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if args[1] == "test" {
+        let (positional, flags) = split_flags(&args[2..]);
+        let source = fs::read_to_string(&positional[0]).expect("File read error");
+        let spec = fs::read_to_string(&positional[1]).expect("File read error");
+        let run = if json_format(&flags) { testcase::run_json } else { testcase::run };
+        match run(&source, &spec, dialect(&flags), &mut io::stdout()) {
+            Ok(true) => return,
+            Ok(false) => process::exit(1),
+            Err(error) => {
+                eprintln!("{}", error);
+                process::exit(2);
+            }
+        }
+    }
+
+    if args[1] == "grade" {
+        let (positional, flags) = split_flags(&args[2..]);
+        let csv_path = PathBuf::from(flags.get("--csv").map_or("grades.csv", String::as_str));
+        let junit_path = PathBuf::from(flags.get("--junit").map_or("grades.xml", String::as_str));
+        let spec = fs::read_to_string(&positional[1]).expect("File read error");
+        if let Err(error) = grade::run(Path::new(&positional[0]), &spec, dialect(&flags), &csv_path, &junit_path, &mut io::stdout()) {
+            eprintln!("{}", error);
+            process::exit(2);
+        }
+        return;
+    }
+
+    if args[1] == "coverage" {
+        let (positional, flags) = split_flags(&args[2..]);
+        let file_path = &positional[0];
+        let int = run_cases(file_path, positional.get(1), &flags, CostModel::default());
+        print!("{}", coverage::annotated(&int));
+        if let Some(html_path) = flags.get("--html") {
+            fs::write(html_path, coverage::html(&int, file_path)).expect("File write error");
+        }
+        if let Some(lcov_path) = flags.get("--lcov") {
+            fs::write(lcov_path, coverage::lcov(&int, file_path)).expect("File write error");
+        }
+        return;
+    }
+
+    if args[1] == "profile" {
+        let (positional, flags) = split_flags(&args[2..]);
+        let costs = match flags.get("--costs") {
+            Some(costs_path) => {
+                let text = fs::read_to_string(costs_path).expect("File read error");
+                CostModel::parse(&text).unwrap_or_else(|error| {
+                    eprintln!("{}", error);
+                    process::exit(2);
+                })
+            }
+            None => CostModel::default(),
+        };
+        let int = run_cases(&positional[0], positional.get(1), &flags, costs);
+        let report = if json_format(&flags) { format!("{}\n", profile::json(&int)) } else { profile::report(&int) };
+        match flags.get("--out") {
+            Some(out_path) => fs::write(out_path, report).expect("File write error"),
+            None => print!("{}", report),
+        }
+        return;
+    }
+
+    if args[1] == "cfg" {
+        let (positional, flags) = split_flags(&args[2..]);
+        let graph = if flags.contains_key("--counts") {
+            graph::dot(&run_cases(&positional[0], positional.get(1), &flags, CostModel::default()), true)
+        } else {
+            let source = fs::read_to_string(&positional[0]).expect("File read error");
+            let mut int = Interpreter::new(source);
+            int.extensions = dialect(&flags);
+            graph::dot(&int, false)
+        };
+        match flags.get("--out") {
+            Some(out_path) => fs::write(out_path, graph).expect("File write error"),
+            None => print!("{}", graph),
+        }
+        return;
+    }
+
+    if args[1] == "decompile" {
+        let (positional, flags) = split_flags(&args[2..]);
+        let source = fs::read_to_string(&positional[0]).expect("File read error");
+        let mut int = Interpreter::new(source);
+        int.extensions = dialect(&flags);
+        let pseudocode = decompile::decompile(&int).unwrap_or_else(|(line, trap)| {
+            eprintln!("line {}: {}", line, trap);
+            process::exit(2);
+        });
+        match flags.get("--out") {
+            Some(out_path) => fs::write(out_path, pseudocode).expect("File write error"),
+            None => print!("{}", pseudocode),
+        }
+        return;
+    }
+
+    if args[1] == "compile" {
+        let (positional, flags) = split_flags(&args[2..]);
+        let source = fs::read_to_string(&positional[0]).expect("File read error");
+        let assembly = compile::compile(&source).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            process::exit(2);
+        });
+        match flags.get("--out") {
+            Some(out_path) => fs::write(out_path, assembly).expect("File write error"),
+            None => print!("{}", assembly),
+        }
+        return;
+    }
+
+    if args[1] == "lsp" {
+        let (_, flags) = split_flags(&args[2..]);
+        if let Err(error) = lsp::run(&mut io::stdin().lock(), &mut io::stdout(), dialect(&flags)) {
+            eprintln!("{}", error);
+            process::exit(2);
+        }
+        return;
+    }
+
+    if args[1] == "dap" {
+        if let Err(error) = dap::run(io::BufReader::new(io::stdin()), &mut io::stdout()) {
+            eprintln!("{}", error);
+            process::exit(2);
+        }
+        return;
+    }
+
+    if args[1] == "gdb" {
+        let (positional, flags) = split_flags(&args[2..]);
+        let port = flags.get("--port").map_or(1234, |port| port.parse().expect("Invalid port"));
+        if let Err(error) = gdb::run(load(&positional[0], &flags), port) {
+            eprintln!("{}", error);
+            process::exit(2);
+        }
+        return;
+    }
+
+    if args[1] == "serve" {
+        let (positional, flags) = split_flags(&args[2..]);
+        let source = positional.first().map_or(String::new(), |file_path| fs::read_to_string(file_path).expect("File read error"));
+        let port = flags.get("--port").map_or(8000, |port| port.parse().expect("Invalid port"));
+        if let Err(error) = serve::run(source, dialect(&flags), port) {
+            eprintln!("{}", error);
+            process::exit(2);
+        }
+        return;
+    }
+
+    if args[1] == "assemble" {
+        let (positional, flags) = split_flags(&args[2..]);
+        let source = fs::read_to_string(&positional[0]).expect("File read error");
+        let mut int = Interpreter::new(source);
+        int.extensions = dialect(&flags);
+        let image = encoding::assemble(&int.source, int.extensions).unwrap_or_else(|(line, trap)| {
+            eprintln!("line {}: {}", line, trap);
+            process::exit(2);
+        });
+        match flags.get("--out") {
+            Some(out_path) if out_path.ends_with(".bin") => {
+                fs::write(out_path, object::to_bin(&image)).expect("File write error")
+            }
+            Some(out_path) => fs::write(out_path, object::to_hex(&image, &int.source)).expect("File write error"),
+            None => print!("{}", object::to_hex(&image, &int.source)),
+        }
+        return;
+    }
+
+    if args[1] == "disassemble" {
+        let (positional, flags) = split_flags(&args[2..]);
+        let bytes = fs::read(&positional[0]).expect("File read error");
+        let source = object::read(&bytes)
+            .and_then(|words| object::disassemble(&words))
+            .unwrap_or_else(|error| {
+                eprintln!("{}", error);
+                process::exit(2);
+            });
+        match flags.get("--out") {
+            Some(out_path) => fs::write(out_path, source).expect("File write error"),
+            None => print!("{}", source),
+        }
+        return;
+    }
+
+    if args[1] == "fmt" {
+        let (positional, flags) = split_flags(&args[2..]);
+        let mut unformatted = false;
+        for file_path in &positional {
+            let source = fs::read_to_string(file_path).expect("File read error");
+            let formatted = format::format(&source);
+            if formatted == source {
+                continue;
+            }
+            if flags.contains_key("--check") {
+                println!("{} is not formatted", file_path);
+                unformatted = true;
+            } else {
+                fs::write(file_path, formatted).expect("File write error");
+            }
+        }
+        if unformatted {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if args[1] == "lint" {
+        let (positional, flags) = split_flags(&args[2..]);
+        let mut clean = true;
+        for file_path in &positional {
+            for warning in lint::lint(&load(file_path, &flags)) {
+                println!("{}: {}", file_path, warning);
+                clean = false;
+            }
+        }
+        if !clean {
+            process::exit(1);
+        }
+        return;
+    }
+
+    let (positional, flags) = split_flags(&args[1..]);
+    let mut int = load(&positional[0], &flags);
+    int.console.echo = true;
 
-    let mut int = Interpreter::new(source);
-    
     let mut program_running = true;
     while program_running {
         int.menu(&mut program_running);
@@ -619,7 +1059,7 @@ mod tests {
         let source = String::from("LDR R0, 42");
         let mut test = Interpreter::new(source);
         test.mem[42] = 123;
-        test.tick();
+        test.tick().unwrap();
         assert_eq!(test.reg[0], 123);
     }
 
@@ -628,7 +1068,7 @@ mod tests {
         let source = String::from("STR R1, 21");
         let mut test = Interpreter::new(source);
         test.reg[1] = 67;
-        test.tick();
+        test.tick().unwrap();
         assert_eq!(test.mem[21], 67);
     }
 
@@ -639,8 +1079,8 @@ mod tests {
         test.reg[1] = 2;
         test.reg[3] = 60;
         test.reg[4] = 9;
-        test.tick();
-        test.tick();
+        test.tick().unwrap();
+        test.tick().unwrap();
         assert_eq!(test.reg[0], 5);
         assert_eq!(test.reg[2], 69);
     }
@@ -652,8 +1092,8 @@ mod tests {
         test.reg[1] = 100;
         test.reg[3] = 42;
         test.reg[4] = 12;
-        test.tick();
-        test.tick();
+        test.tick().unwrap();
+        test.tick().unwrap();
         assert_eq!(test.reg[0], 45);
         assert_eq!(test.reg[2], 30);
     }
@@ -663,8 +1103,8 @@ mod tests {
         let source = String::from("MOV R0, #456\nMOV R1, R2");
         let mut test = Interpreter::new(source);
         test.reg[2] = 678;
-        test.tick();
-        test.tick();
+        test.tick().unwrap();
+        test.tick().unwrap();
         assert_eq!(test.reg[0], 456);
         assert_eq!(test.reg[1], 678);
     }
@@ -674,25 +1114,25 @@ mod tests {
         let source = String::from("CMP R0, #2\nCMP R1, R2\nCMP R3, #3\nCMP R4, R5\nCMP R6, #4\nCMP R7, R8");
         let mut test = Interpreter::new(source);
         test.reg[0] = 1;
-        test.tick();
+        test.tick().unwrap();
         assert_eq!(test.cmp, Compare::LT);
         test.reg[1] = 5;
         test.reg[2] = 10;
-        test.tick();
+        test.tick().unwrap();
         assert_eq!(test.cmp, Compare::LT);
         test.reg[3] = 6;
-        test.tick();
+        test.tick().unwrap();
         assert_eq!(test.cmp, Compare::GT);
         test.reg[4] = 42;
         test.reg[5] = 21;
-        test.tick();
+        test.tick().unwrap();
         assert_eq!(test.cmp, Compare::GT);
         test.reg[6] = 4;
-        test.tick();
+        test.tick().unwrap();
         assert_eq!(test.cmp, Compare::EQ);
         test.reg[7] = 8;
         test.reg[8] = 8;
-        test.tick();
+        test.tick().unwrap();
         assert_eq!(test.cmp, Compare::EQ);
     }
 
@@ -700,7 +1140,7 @@ mod tests {
     fn b() {
         let source = String::from("B label\nlabel:\nHALT");
         let mut test = Interpreter::new(source);
-        test.tick();
+        test.tick().unwrap();
         assert_eq!(test.line_num, 2);
     }
 
@@ -709,8 +1149,8 @@ mod tests {
         let source = String::from("CMP R0, #42\nBEQ label\nHALT\nlabel:\nHALT");
         let mut test = Interpreter::new(source);
         test.reg[0] = 42;
-        test.tick();
-        test.tick();
+        test.tick().unwrap();
+        test.tick().unwrap();
         assert_eq!(test.line_num, 4);
     }
 
@@ -719,8 +1159,8 @@ mod tests {
         let source = String::from("CMP R0, #42\nBNE label\nHALT\nlabel:\nHALT");
         let mut test = Interpreter::new(source);
         test.reg[0] = 123;
-        test.tick();
-        test.tick();
+        test.tick().unwrap();
+        test.tick().unwrap();
         assert_eq!(test.line_num, 4);
     }
 
@@ -729,8 +1169,8 @@ mod tests {
         let source = String::from("CMP R0, #42\nBGT label\nHALT\nlabel:\nHALT");
         let mut test = Interpreter::new(source);
         test.reg[0] = 123;
-        test.tick();
-        test.tick();
+        test.tick().unwrap();
+        test.tick().unwrap();
         assert_eq!(test.line_num, 4);
     }
 
@@ -739,8 +1179,8 @@ mod tests {
         let source = String::from("CMP R0, #42\nBLT label\nHALT\nlabel:\nHALT");
         let mut test = Interpreter::new(source);
         test.reg[0] = 21;
-        test.tick();
-        test.tick();
+        test.tick().unwrap();
+        test.tick().unwrap();
         assert_eq!(test.line_num, 4);
     }
 
@@ -751,8 +1191,8 @@ mod tests {
         test.reg[1] = 15;
         test.reg[3] = 11;
         test.reg[4] = 2;
-        test.tick();
-        test.tick();
+        test.tick().unwrap();
+        test.tick().unwrap();
         assert_eq!(test.reg[0], 10);
         assert_eq!(test.reg[2], 2);
     }
@@ -764,8 +1204,8 @@ mod tests {
         test.reg[1] = 6;
         test.reg[3] = 8;
         test.reg[4] = 2;
-        test.tick();
-        test.tick();
+        test.tick().unwrap();
+        test.tick().unwrap();
         assert_eq!(test.reg[0], 15);
         assert_eq!(test.reg[2], 10);
     }
@@ -777,8 +1217,8 @@ mod tests {
         test.reg[1] = 15;
         test.reg[3] = 11;
         test.reg[4] = 2;
-        test.tick();
-        test.tick();
+        test.tick().unwrap();
+        test.tick().unwrap();
         assert_eq!(test.reg[0], 6);
         assert_eq!(test.reg[2], 9);
     }
@@ -794,7 +1234,7 @@ mod tests {
         let source = String::from("LSL R0, R1, #1");
         let mut test = Interpreter::new(source);
         test.reg[1] = 4;
-        test.tick();
+        test.tick().unwrap();
         assert_eq!(test.reg[0], 8);
     }
 
@@ -803,7 +1243,7 @@ mod tests {
         let source = String::from("LSR R0, R1, #1");
         let mut test = Interpreter::new(source);
         test.reg[1] = 4;
-        test.tick();
+        test.tick().unwrap();
         assert_eq!(test.reg[0], 2);
     }
 
@@ -811,7 +1251,232 @@ mod tests {
     fn halt() {
         let source = String::from("HALT");
         let mut test = Interpreter::new(source);
-        test.tick();
+        test.tick().unwrap();
         assert!(!test.running);
     }
+
+    #[test]
+    fn branch_not_taken() {
+        let source = String::from("CMP R0, #1\nBEQ label\nMOV R1, #5\nlabel:\nHALT");
+        let mut test = Interpreter::new(source);
+        test.tick().unwrap();
+        test.tick().unwrap();
+        assert_eq!(test.line_num, 2);
+        test.tick().unwrap();
+        assert_eq!(test.reg[1], 5);
+    }
+
+    #[test]
+    fn traps() {
+        let source = String::from("LDR R0, 256\nMOV R16, #1\nB nowhere\nJMP label\nADD R0 R1, #1");
+        let mut test = Interpreter::new(source);
+        assert_eq!(test.tick(), Err(Trap::Address(String::from("256"))));
+        assert_eq!(test.line_num, 0);
+        test.line_num = 1;
+        assert_eq!(test.tick(), Err(Trap::Syntax(String::from("invalid register 'R16'"))));
+        test.line_num = 2;
+        assert_eq!(test.tick(), Err(Trap::Label(String::from("nowhere"))));
+        test.line_num = 3;
+        assert_eq!(test.tick(), Err(Trap::Syntax(String::from("unknown instruction 'JMP'"))));
+        test.line_num = 4;
+        assert_eq!(test.tick(), Err(Trap::Syntax(String::from("expected ',' after 'R0'"))));
+    }
+
+    #[test]
+    fn check() {
+        let source = String::from("MOV R0, #1\nHALT\nSTR R0, 300\nloop:\nB loop");
+        let test = Interpreter::new(source);
+        assert_eq!(test.check(), vec![(2, Trap::Address(String::from("300")))]);
+    }
+
+    #[test]
+    fn stats() {
+        let source = String::from("loop:\nADD R0, R0, #1\nCMP R0, #3\nBLT loop\nHALT");
+        let mut test = Interpreter::new(source);
+        while test.running {
+            test.tick().unwrap();
+        }
+        test.reset();
+        test.tick().unwrap();
+        test.tick().unwrap();
+        assert_eq!(test.stats.line_hits, vec![0, 4, 3, 3, 1]);
+        assert_eq!(test.stats.branches[3], (2, 1));
+
+        let source = String::from("LDR R0, 1\nLDR R1, 2\nSTR R0, 3\nHALT");
+        let mut test = Interpreter::new(source);
+        while test.running {
+            test.tick().unwrap();
+        }
+        assert_eq!((test.stats.mem_reads, test.stats.mem_writes), (2, 1));
+        assert_eq!(test.stats.cycles, 13);
+    }
+
+    #[test]
+    fn micro_tick() {
+        let source = String::from("LDR R0, 40\nADD R1, R0, #2\nHALT");
+        let mut test = Interpreter::new(source);
+        test.mem[40] = 5;
+
+        test.micro_tick().unwrap();
+        assert_eq!(test.fde.phase, Phase::Decode);
+        assert_eq!((test.line_num, test.fde.mar), (1, 0));
+        assert_eq!(test.fde.cir, Word::Text(String::from("LDR R0, 40")));
+        test.micro_tick().unwrap();
+        assert_eq!(test.fde.instruction, Some(Instruction::Ldr(0, MemoryRef::Direct(40))));
+        test.micro_tick().unwrap();
+        assert_eq!(test.fde.phase, Phase::Fetch);
+        assert_eq!((test.fde.mar, &test.fde.mdr), (40, &Word::Value(5)));
+        assert_eq!(test.reg[0], 5);
+
+        test.micro_tick().unwrap();
+        test.tick().unwrap();
+        assert_eq!((test.reg[1], test.fde.acc), (7, 7));
+        assert_eq!(test.line_num, 2);
+    }
+
+    #[test]
+    fn trap_rewinds_pc() {
+        let source = String::from("MOV R0, #1\nMOV R0, 1");
+        let mut test = Interpreter::new(source);
+        test.tick().unwrap();
+        assert!(test.tick().is_err());
+        assert_eq!((test.line_num, test.fde.phase), (1, Phase::Fetch));
+    }
+
+    #[test]
+    fn von_neumann() {
+        let source = String::from("MOV R0, #0\nloop:\nADD R0, R0, #1\nCMP R0, #3\nBLT loop\nHALT");
+        let mut test = Interpreter::new(source);
+        test.load_into_memory().unwrap();
+        assert_eq!(decode_word(test.mem[1]), Ok(Instruction::Add(0, 0, Operand::Immediate(1))));
+        while test.running {
+            test.tick().unwrap();
+        }
+        assert_eq!((test.reg[0], test.line_num), (3, 5));
+        assert_eq!(test.fde.cir, Word::Value(0));
+        assert_eq!(test.stats.line_hits, vec![1, 0, 3, 3, 3, 1]);
+        assert_eq!(test.stats.branches[4], (2, 1));
+
+        test.reset();
+        assert_eq!(decode_word(test.mem[4]), Ok(Instruction::Halt));
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        // Overwrites the MOV at address 2 with the HALT word held in R1.
+        let source = String::from("MOV R1, #0\nSTR R1, 2\nMOV R0, #7\nHALT");
+        let mut test = Interpreter::new(source);
+        test.load_into_memory().unwrap();
+        while test.running {
+            test.tick().unwrap();
+        }
+        assert_eq!((test.reg[0], test.line_num), (0, 3));
+    }
+
+    #[test]
+    fn end_of_source() {
+        let source = String::from("MOV R0, #1");
+        let mut test = Interpreter::new(source);
+        test.tick().unwrap();
+        test.tick().unwrap();
+        assert!(!test.running);
+        assert_eq!(test.line_num, 1);
+    }
+
+    #[test]
+    fn devices() {
+        let source = String::from("MOV R0, #0\nSTR R0, 242\nLDR R1, 242\nLDR R2, 242\nSTR R1, 242\nHALT");
+        let mut test = Interpreter::new(source);
+        test.devices = Devices::parse("timer").unwrap();
+        while test.running {
+            test.tick().unwrap();
+        }
+        // The timer counts the STR that cleared it.
+        assert_eq!((test.reg[1], test.reg[2]), (1, 2));
+        assert_eq!(test.mem[242], 0);
+        test.reset();
+        test.tick().unwrap();
+        test.tick().unwrap();
+        test.tick().unwrap();
+        assert_eq!(test.reg[1], 1);
+    }
+
+    #[test]
+    fn indexed_addressing() {
+        // Sums the array at mem[10..13] by walking a pointer through it.
+        let source = "MOV R1, #10\nloop:\nLDR R2, [R1]\nADD R0, R0, R2\nADD R1, R1, #1\nCMP R1, #13\nBLT loop\nSTR R0, [R1, #-4]\nHALT";
+        let mut test = Interpreter::new(String::from(source));
+        test.extensions.indirect = true;
+        test.mem[10..13].copy_from_slice(&[4, 5, 6]);
+        while test.running {
+            test.tick().unwrap();
+        }
+        assert_eq!((test.reg[0], test.mem[9]), (15, 15));
+
+        let mut test = Interpreter::new(String::from("MOV R1, #250\nLDR R0, [R1, #6]"));
+        test.extensions.indirect = true;
+        test.tick().unwrap();
+        assert_eq!(test.tick(), Err(Trap::Address(String::from("256"))));
+        let mut test = Interpreter::new(String::from("LDR R0, [R1]"));
+        assert_eq!(test.tick(), Err(Trap::Syntax(String::from("LDR needs the indirect extension"))));
+    }
+
+    #[test]
+    fn subroutines() {
+        let source = String::from("MOV R0, #3\nBL double\nHALT\ndouble:\nPUSH R14\nPUSH R0\nBL add\nPOP R1\nPOP R14\nRET\nadd:\nADD R0, R0, R0\nRET");
+        let mut test = Interpreter::new(source);
+        test.extensions.stack = true;
+        test.reset();
+        assert_eq!(test.reg[SP], 240);
+        while test.line_num != 11 {
+            test.tick().unwrap();
+        }
+        // Inside `add`, called from `double`, called from the top.
+        assert_eq!(test.call_stack(), vec![7, 2]);
+        assert_eq!((test.reg[SP], test.mem[238], test.mem[239]), (238, 3, 2));
+        while test.running {
+            test.tick().unwrap();
+        }
+        assert_eq!((test.reg[0], test.reg[1], test.reg[SP]), (6, 3, 240));
+        assert!(test.call_stack().is_empty());
+    }
+
+    #[test]
+    fn stack_traps() {
+        let mut test = Interpreter::new(String::from("POP R0"));
+        test.extensions.stack = true;
+        test.reset();
+        assert_eq!(test.tick(), Err(Trap::StackUnderflow));
+
+        let mut test = Interpreter::new(String::from("loop:\nPUSH R0\nB loop"));
+        test.extensions.stack = true;
+        test.reset();
+        let trap = (0..100).find_map(|_| test.tick().err());
+        assert_eq!((trap, test.reg[SP]), (Some(Trap::StackOverflow), 208));
+
+        let mut test = Interpreter::new(String::from("MOV R13, #10\nPUSH R0"));
+        test.extensions.stack = true;
+        test.tick().unwrap();
+        assert_eq!(test.tick(), Err(Trap::Address(String::from("10"))));
+        assert!(Interpreter::new(String::from("RET")).tick().is_err());
+    }
+
+    #[test]
+    fn stack_and_devices() {
+        // The built-in devices sit above the stack, so filling it leaves them alone.
+        let mut test = Interpreter::new(String::from("MOV R0, #65\nloop:\nPUSH R0\nB loop"));
+        test.extensions.stack = true;
+        test.devices = Devices::parse("console").unwrap();
+        test.reset();
+        while test.tick().is_ok() {}
+        assert_eq!(test.console.output, "");
+        assert_eq!((test.mem[208], test.mem[240]), (65, 0));
+
+        // A device moved into the stack traps instead of being written.
+        let mut test = Interpreter::new(String::from("PUSH R0"));
+        test.extensions.stack = true;
+        test.devices = Devices::parse("console@239").unwrap();
+        test.reset();
+        assert_eq!(test.tick(), Err(Trap::Address(String::from("239"))));
+    }
 }
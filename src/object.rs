@@ -0,0 +1,169 @@
+use std::collections::BTreeSet;
+
+use crate::encoding::Image;
+use crate::encoding::decode_word;
+use crate::instruction::Instruction;
+
+// Binary object files start with this, followed by each word little-endian.
+const MAGIC: &[u8; 4] = b"IAN1";
+
+// One word per line as eight hex digits. Everything after `;` is a comment,
+// used here to show the address and the source line the word came from.
+pub fn to_hex(image: &Image, source: &[String]) -> String {
+    let mut hex = String::new();
+    for (address, (word, line)) in image.words.iter().zip(&image.lines).enumerate() {
+        hex.push_str(&format!("{:08x}  ; {:>3}: {}\n", *word as u32, address, source[*line].trim()));
+    }
+    hex
+}
+
+pub fn to_bin(image: &Image) -> Vec<u8> {
+    let mut bin = MAGIC.to_vec();
+    for word in &image.words {
+        bin.extend_from_slice(&word.to_le_bytes());
+    }
+    bin
+}
+
+// Reads either format, telling them apart by the magic number.
+pub fn read(bytes: &[u8]) -> Result<Vec<i32>, String> {
+    let words = match bytes.strip_prefix(MAGIC) {
+        Some(body) => read_bin(body)?,
+        None => read_hex(bytes)?,
+    };
+    if words.len() > 256 {
+        return Err(String::from("image does not fit in memory"));
+    }
+    Ok(words)
+}
+
+fn read_bin(body: &[u8]) -> Result<Vec<i32>, String> {
+    if !body.len().is_multiple_of(4) {
+        return Err(String::from("binary image is not a whole number of words"));
+    }
+    Ok(body
+        .chunks(4)
+        .map(|chunk| i32::from_le_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
+
+fn read_hex(bytes: &[u8]) -> Result<Vec<i32>, String> {
+    let text = std::str::from_utf8(bytes).map_err(|_| String::from("image is neither binary nor hex text"))?;
+    let mut words = vec![];
+    for (index, line) in text.lines().enumerate() {
+        let digits = line.split(';').next().unwrap().trim();
+        if digits.is_empty() {
+            continue;
+        }
+        let word = u32::from_str_radix(digits, 16)
+            .map_err(|_| format!("hex line {}: invalid word '{}'", index + 1, digits))?;
+        words.push(word as i32);
+    }
+    Ok(words)
+}
+
+// Turns words back into source. Every branch target gets a synthetic label
+// `L<address>`, so the output assembles back to the same words.
+pub fn disassemble(words: &[i32]) -> Result<String, String> {
+    let instructions = words
+        .iter()
+        .enumerate()
+        .map(|(address, word)| {
+            decode_word(*word).map_err(|trap| format!("address {}: {}", address, trap))
+        })
+        .collect::<Result<Vec<Instruction>, String>>()?;
+
+    let targets: BTreeSet<usize> = instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::Branch(_, target) | Instruction::Bl(target) => Some(*target),
+            _ => None,
+        })
+        .collect();
+
+    let mut source = String::new();
+    for (address, instruction) in instructions.iter().enumerate() {
+        if targets.contains(&address) {
+            source.push_str(&format!("L{}:\n", address));
+        }
+        match instruction {
+            Instruction::Branch(_, target) | Instruction::Bl(target) => {
+                source.push_str(&format!("{} L{}\n", instruction.mnemonic(), target))
+            }
+            _ => source.push_str(&format!("{}\n", instruction)),
+        }
+    }
+    // Branches past the last instruction still need somewhere to land.
+    for target in targets.range(instructions.len()..) {
+        source.push_str(&format!("L{}:\n", target));
+    }
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::assemble;
+    use crate::instruction::Extensions;
+
+    const PROGRAM: &str = "MOV R0, #0\nloop:\nADD R0, R0, #1\nCMP R0, #10\nBLT loop\nBEQ end\nSTR R0, 100\nend:";
+
+    fn lines(source: &str) -> Vec<String> {
+        source.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let source = lines(PROGRAM);
+        let image = assemble(&source, Extensions::default()).unwrap();
+        let hex = to_hex(&image, &source);
+        assert!(hex.starts_with("28040000  ;   0: MOV R0, #0\n"));
+        assert_eq!(read(hex.as_bytes()), Ok(image.words));
+    }
+
+    #[test]
+    fn bin_round_trip() {
+        let image = assemble(&lines(PROGRAM), Extensions::default()).unwrap();
+        let bin = to_bin(&image);
+        assert_eq!(&bin[..4], b"IAN1");
+        assert_eq!(bin.len(), 4 + 4 * image.words.len());
+        assert_eq!(read(&bin), Ok(image.words));
+    }
+
+    #[test]
+    fn disassembles_branch_past_the_end() {
+        let full = format!("{}B end\nend:", "MOV R0, #1\n".repeat(255));
+        let image = assemble(&lines(&full), Extensions::default()).unwrap();
+        let source = disassemble(&image.words).unwrap();
+        assert!(source.ends_with("MOV R0, #1\nB L256\nL256:\n"));
+        assert_eq!(assemble(&lines(&source), Extensions::default()).unwrap().words, image.words);
+    }
+
+    #[test]
+    fn disassembles_with_labels() {
+        let image = assemble(&lines(PROGRAM), Extensions::default()).unwrap();
+        let source = disassemble(&image.words).unwrap();
+        assert_eq!(
+            source,
+            "MOV R0, #0\nL1:\nADD R0, R0, #1\nCMP R0, #10\nBLT L1\nBEQ L6\nSTR R0, 100\nL6:\n"
+        );
+        assert_eq!(assemble(&lines(&source), Extensions::default()).unwrap().words, image.words);
+        let calls = assemble(&lines("BL sub\nsub:\nRET"), Extensions::ALL).unwrap();
+        assert_eq!(disassemble(&calls.words).unwrap(), "BL L1\nL1:\nRET\n");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(read(b"123x"), Err(String::from("hex line 1: invalid word '123x'")));
+        assert_eq!(read(b"IAN1\x00\x00"), Err(String::from("binary image is not a whole number of words")));
+        // Like an assembled program, an image must fit in the 256 words of memory.
+        let bin = |words: usize| [MAGIC.to_vec(), vec![0; words * 4]].concat();
+        assert_eq!(read(&bin(256)).map(|words| words.len()), Ok(256));
+        assert_eq!(read(&bin(257)), Err(String::from("image does not fit in memory")));
+        assert_eq!(read("00000000\n".repeat(257).as_bytes()), Err(String::from("image does not fit in memory")));
+        assert_eq!(
+            disassemble(&[-1]),
+            Err(String::from("address 0: invalid instruction word 0xffffffff"))
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+
+use crate::Interpreter;
+use crate::instruction::code;
+use crate::instruction::label;
+use crate::json::Json;
+use crate::json::object;
+
+const HOTTEST_LINES: usize = 10;
+
+// Lines before the first label are counted against this block.
+const ENTRY_BLOCK: &str = "(entry)";
+
+struct Profile {
+    total: u64,
+    hottest: Vec<(usize, u64)>,
+    blocks: Vec<(String, u64)>,
+    opcodes: BTreeMap<String, u64>,
+}
+
+fn profile(int: &Interpreter) -> Profile {
+    let hits = &int.stats.line_hits;
+
+    let mut hottest: Vec<(usize, u64)> = hits
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    hottest.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    hottest.truncate(HOTTEST_LINES);
+
+    let mut blocks = vec![(String::from(ENTRY_BLOCK), 0)];
+    let mut entry_has_code = false;
+    let mut opcodes = BTreeMap::new();
+    for (index, line) in int.source.iter().enumerate() {
+        if let Some(label) = label(line) {
+            blocks.push((label.to_string(), 0));
+            continue;
+        }
+        let Some(mnemonic) = code(line).split_whitespace().next() else {
+            continue;
+        };
+        entry_has_code |= blocks.len() == 1;
+        if hits[index] == 0 {
+            continue;
+        }
+        blocks.last_mut().unwrap().1 += hits[index];
+        *opcodes.entry(mnemonic.to_string()).or_insert(0) += hits[index];
+    }
+    // Drop the entry block when the program starts with a label.
+    if !entry_has_code {
+        blocks.remove(0);
+    }
+
+    Profile {
+        total: hits.iter().sum(),
+        hottest,
+        blocks,
+        opcodes,
+    }
+}
+
+pub fn report(int: &Interpreter) -> String {
+    let profile = profile(int);
+    let mut report = String::new();
+
+    report.push_str(&format!("Instructions executed: {}\n", profile.total));
+    report.push_str(&format!("Cycles: {}", int.stats.cycles));
+    if profile.total > 0 {
+        report.push_str(&format!(" ({:.2} per instruction)", int.stats.cycles as f64 / profile.total as f64));
+    }
+    report.push('\n');
+    report.push_str(&format!("Memory reads: {}\n", int.stats.mem_reads));
+    report.push_str(&format!("Memory writes: {}\n", int.stats.mem_writes));
+
+    report.push_str("\nHottest lines:\n");
+    report.push_str(&format!("{:>10}  {:>4}  {}\n", "count", "line", "source"));
+    for (index, count) in &profile.hottest {
+        report.push_str(&format!("{:>10}  {:>4}  {}\n", count, index, int.source[*index].trim()));
+    }
+
+    report.push_str("\nBy block:\n");
+    for (label, count) in &profile.blocks {
+        report.push_str(&format!("{:>10}  {}\n", count, label));
+    }
+
+    report.push_str("\nBy opcode:\n");
+    report.push_str(&format!("{:>10}  {:>10}  {}\n", "count", "cycles", "opcode"));
+    for (mnemonic, count) in &profile.opcodes {
+        let cycles = count * int.costs.cost(mnemonic);
+        report.push_str(&format!("{:>10}  {:>10}  {}\n", count, cycles, mnemonic));
+    }
+    report
+}
+
+pub fn json(int: &Interpreter) -> Json {
+    let profile = profile(int);
+    let hottest = profile.hottest.iter().map(|&(index, count)| {
+        object([("line", index.into()), ("count", count.into()), ("source", int.source[index].trim().into())])
+    });
+    let blocks = profile.blocks.iter().map(|(label, count)| object([("label", label.as_str().into()), ("count", (*count).into())]));
+    let opcodes = profile.opcodes.iter().map(|(mnemonic, &count)| {
+        object([
+            ("opcode", mnemonic.as_str().into()),
+            ("count", count.into()),
+            ("cycles", (count * int.costs.cost(mnemonic)).into()),
+        ])
+    });
+    object([
+        ("instructions", profile.total.into()),
+        ("cycles", int.stats.cycles.into()),
+        ("memoryReads", int.stats.mem_reads.into()),
+        ("memoryWrites", int.stats.mem_writes.into()),
+        ("hottest", hottest.collect::<Vec<_>>().into()),
+        ("blocks", blocks.collect::<Vec<_>>().into()),
+        ("opcodes", opcodes.collect::<Vec<_>>().into()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str) -> Interpreter {
+        let mut int = Interpreter::new(source.to_string());
+        while int.running {
+            int.tick().unwrap();
+        }
+        int
+    }
+
+    #[test]
+    fn counts() {
+        let int = run("MOV R0, #0\nloop:\nADD R0, R0, #1\nCMP R0, #4\nBLT loop\nSTR R0, 10\ndone:\nHALT");
+        let profile = profile(&int);
+        assert_eq!(profile.total, 15);
+        assert_eq!(profile.hottest[..3], [(2, 4), (3, 4), (4, 4)]);
+        assert_eq!(
+            profile.blocks,
+            vec![(String::from("(entry)"), 1), (String::from("loop"), 13), (String::from("done"), 1)]
+        );
+        assert_eq!(profile.opcodes.get("ADD"), Some(&4));
+        assert_eq!(profile.opcodes.get("HALT"), Some(&1));
+    }
+
+    #[test]
+    fn report_layout() {
+        let int = run("start:\nLDR R0, 1\nSTR R0, 2\nHALT");
+        let report = report(&int);
+        assert!(report.starts_with(
+            "Instructions executed: 3\nCycles: 9 (3.00 per instruction)\nMemory reads: 1\nMemory writes: 1\n"
+        ));
+        assert!(report.contains("         1           4  LDR\n"));
+        assert!(report.contains("\nBy block:\n         3  start\n\n"));
+        assert!(!report.contains("(entry)"));
+    }
+
+    #[test]
+    fn json_layout() {
+        let json = json(&run("start:\nLDR R0, 1\nHALT"));
+        assert_eq!(
+            json.to_string(),
+            concat!(
+                r#"{"instructions":2,"cycles":5,"memoryReads":1,"memoryWrites":0,"#,
+                r#""hottest":[{"line":1,"count":1,"source":"LDR R0, 1"},{"line":2,"count":1,"source":"HALT"}],"#,
+                r#""blocks":[{"label":"start","count":2}],"#,
+                r#""opcodes":[{"opcode":"HALT","count":1,"cycles":1},{"opcode":"LDR","count":1,"cycles":4}]}"#
+            )
+        );
+    }
+}
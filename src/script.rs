@@ -0,0 +1,270 @@
+use std::io::Write;
+
+use crate::Compare;
+use crate::Interpreter;
+
+// `run` without an explicit limit gives up after this many steps so a looping
+// program can't hang an automated session.
+const RUN_LIMIT: usize = 1_000_000;
+
+#[derive(Clone, Copy)]
+pub enum Location {
+    Reg(usize),
+    Mem(usize),
+    Cmp,
+    Line,
+    Halted,
+}
+
+pub fn parse_location(token: &str) -> Result<Location, String> {
+    match token {
+        "cmp" => Ok(Location::Cmp),
+        "line" => Ok(Location::Line),
+        "halted" => Ok(Location::Halted),
+        _ => {
+            if let Some(register) = token.strip_prefix('R') {
+                match register.parse::<usize>() {
+                    Ok(register) if register < 16 => Ok(Location::Reg(register)),
+                    _ => Err(format!("invalid register '{}'", token)),
+                }
+            } else {
+                match token.parse::<usize>() {
+                    Ok(address) if address < 256 => Ok(Location::Mem(address)),
+                    _ => Err(format!("invalid location '{}'", token)),
+                }
+            }
+        }
+    }
+}
+
+pub fn parse_value(token: Option<&str>) -> Result<i32, String> {
+    let token = token.ok_or("missing value")?;
+    token
+        .parse::<i32>()
+        .map_err(|_| format!("invalid value '{}'", token))
+}
+
+fn parse_compare(token: Option<&str>) -> Result<Compare, String> {
+    match token {
+        Some("EQ") => Ok(Compare::EQ),
+        Some("GT") => Ok(Compare::GT),
+        Some("LT") => Ok(Compare::LT),
+        Some("NONE") => Ok(Compare::NONE),
+        Some(token) => Err(format!("invalid comparison '{}'", token)),
+        None => Err(String::from("missing comparison")),
+    }
+}
+
+fn describe(int: &Interpreter, location: &Location) -> String {
+    match location {
+        Location::Reg(register) => format!("R{} = {}", register, int.reg[*register]),
+        Location::Mem(address) => format!("mem {} = {}", address, int.mem[*address]),
+        Location::Cmp => format!("cmp = {:?}", int.cmp),
+        Location::Line => format!("line = {}", int.line_num),
+        Location::Halted => format!("halted = {}", !int.running),
+    }
+}
+
+struct Session<'a, W: Write> {
+    int: &'a mut Interpreter,
+    out: &'a mut W,
+    passed: usize,
+    failed: usize,
+}
+
+impl<W: Write> Session<'_, W> {
+    // A trap is reported and counted as a failure, but the session carries on.
+    fn step(&mut self, count: usize) -> Option<usize> {
+        let mut steps = 0;
+        while self.int.running && steps < count {
+            if let Err(trap) = self.int.tick() {
+                self.failed += 1;
+                writeln!(self.out, "TRAP line {}: {}", self.int.line_num, trap).unwrap();
+                return None;
+            }
+            steps += 1;
+        }
+        Some(steps)
+    }
+
+    fn expect(&mut self, location: Location, mut words: std::str::SplitWhitespace, line: usize) -> Result<(), String> {
+        let (matches, wanted) = match location {
+            Location::Reg(register) => {
+                let value = parse_value(words.next())?;
+                (self.int.reg[register] == value, value.to_string())
+            }
+            Location::Mem(address) => {
+                let value = parse_value(words.next())?;
+                (self.int.mem[address] == value, value.to_string())
+            }
+            Location::Cmp => {
+                let value = parse_compare(words.next())?;
+                (self.int.cmp == value, format!("{:?}", value))
+            }
+            Location::Line => {
+                let value = parse_value(words.next())?;
+                (value >= 0 && self.int.line_num == value as usize, value.to_string())
+            }
+            Location::Halted => (!self.int.running, String::from("true")),
+        };
+
+        if matches {
+            self.passed += 1;
+            writeln!(self.out, "PASS {}", describe(self.int, &location)).unwrap();
+        } else {
+            self.failed += 1;
+            writeln!(
+                self.out,
+                "FAIL {} (expected {}, script line {})",
+                describe(self.int, &location),
+                wanted,
+                line
+            )
+            .unwrap();
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, text: &str, line: usize) -> Result<(), String> {
+        let mut words = text.split_whitespace();
+        match words.next() {
+            Some("step") => {
+                let count = match words.next() {
+                    Some(count) => count
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid step count '{}'", count))?,
+                    None => 1,
+                };
+                let Some(steps) = self.step(count) else {
+                    return Ok(());
+                };
+                writeln!(self.out, "step {}: line {}", steps, self.int.line_num).unwrap();
+            }
+
+            Some("run") => {
+                let limit = match words.next() {
+                    Some(limit) => limit
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid step limit '{}'", limit))?,
+                    None => RUN_LIMIT,
+                };
+                let Some(steps) = self.step(limit) else {
+                    return Ok(());
+                };
+                if self.int.running {
+                    writeln!(self.out, "run: step limit reached after {} steps at line {}", steps, self.int.line_num).unwrap();
+                } else {
+                    writeln!(self.out, "run: halted after {} steps at line {}", steps, self.int.line_num).unwrap();
+                }
+            }
+
+            Some("set") => {
+                let location = parse_location(words.next().ok_or("missing location")?)?;
+                let value = parse_value(words.next())?;
+                match location {
+                    Location::Reg(register) => self.int.reg[register] = value,
+                    Location::Mem(address) => self.int.mem[address] = value,
+                    _ => return Err(String::from("only registers and memory can be set")),
+                }
+                writeln!(self.out, "set {}", describe(self.int, &location)).unwrap();
+            }
+
+            Some("print") => {
+                let location = parse_location(words.next().ok_or("missing location")?)?;
+                writeln!(self.out, "{}", describe(self.int, &location)).unwrap();
+            }
+
+            Some("expect") => {
+                let location = parse_location(words.next().ok_or("missing location")?)?;
+                self.expect(location, words, line)?;
+            }
+
+            Some("reset") => {
+                self.int.reset();
+                writeln!(self.out, "reset").unwrap();
+            }
+
+            Some(command) => return Err(format!("unknown command '{}'", command)),
+            None => (),
+        }
+        Ok(())
+    }
+}
+
+// Runs each line of `script` against `int`, writing results to `out`.
+// Returns whether every `expect` passed; syntax errors in the script abort the session.
+pub fn run<W: Write>(int: &mut Interpreter, script: &str, out: &mut W) -> Result<bool, String> {
+    let mut session = Session {
+        int,
+        out,
+        passed: 0,
+        failed: 0,
+    };
+
+    for (index, text) in script.lines().enumerate() {
+        let text = text.split('#').next().unwrap().trim();
+        session
+            .command(text, index + 1)
+            .map_err(|error| format!("script line {}: {}", index + 1, error))?;
+    }
+
+    if session.passed + session.failed > 0 {
+        writeln!(session.out, "{} passed, {} failed", session.passed, session.failed).unwrap();
+    }
+    Ok(session.failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(program: &str, script: &str) -> (Result<bool, String>, String) {
+        let mut int = Interpreter::new(String::from(program));
+        let mut out = Vec::new();
+        let result = run(&mut int, script, &mut out);
+        (result, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn passing_session() {
+        let (result, out) = session(
+            "LDR R0, 40\nADD R1, R0, #2\nSTR R1, 41\nHALT",
+            "set 40 5\nstep\nexpect R0 5\nrun\nexpect 41 7\nexpect halted",
+        );
+        assert_eq!(result, Ok(true));
+        assert_eq!(
+            out,
+            "set mem 40 = 5\nstep 1: line 1\nPASS R0 = 5\nrun: halted after 3 steps at line 4\n\
+             PASS mem 41 = 7\nPASS halted = true\n3 passed, 0 failed\n"
+        );
+    }
+
+    #[test]
+    fn failing_expect() {
+        let (result, out) = session("MOV R0, #1\nCMP R0, #2\nHALT", "run\nexpect R0 2\nexpect cmp LT");
+        assert_eq!(result, Ok(false));
+        assert!(out.contains("FAIL R0 = 1 (expected 2, script line 2)"));
+        assert!(out.contains("PASS cmp = LT"));
+    }
+
+    #[test]
+    fn run_limit() {
+        let (result, out) = session("loop:\nB loop", "run 10\nexpect line 1");
+        assert_eq!(result, Ok(true));
+        assert!(out.contains("run: step limit reached after 10 steps"));
+    }
+
+    #[test]
+    fn trap_fails_session() {
+        let (result, out) = session("MOV R0, #1\nLDR R1, 300\nHALT", "run\nexpect R0 1");
+        assert_eq!(result, Ok(false));
+        assert!(out.contains("TRAP line 1: memory address '300' out of range"));
+        assert!(out.contains("1 passed, 1 failed"));
+    }
+
+    #[test]
+    fn bad_command() {
+        let (result, _) = session("HALT", "# comment\n\njump 4");
+        assert_eq!(result, Err(String::from("script line 3: unknown command 'jump'")));
+    }
+}
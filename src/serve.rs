@@ -0,0 +1,364 @@
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::net::TcpListener;
+use std::time::Duration;
+
+use crate::Interpreter;
+use crate::instruction::Extensions;
+use crate::json::Json;
+use crate::json::object;
+use crate::trace;
+use crate::trace::Step;
+
+const PAGE: &str = include_str!("serve.html");
+// Stops `run` from hanging the server on a program that never halts.
+const RUN_LIMIT: usize = 100_000;
+// Only the latest rows of the trace table are sent to the browser.
+const TRACE_ROWS: usize = 500;
+// Larger bodies are refused rather than allocated, since any client can claim any length.
+const MAX_BODY: usize = 1 << 20;
+// An idle connection is dropped after this, so it can't hold up the ones behind it.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+}
+
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    fn json(json: Json) -> Self {
+        Response {
+            status: "200 OK",
+            content_type: "application/json",
+            body: json.to_string(),
+        }
+    }
+
+    fn error(status: &'static str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: status.to_string(),
+        }
+    }
+}
+
+// Reads one HTTP request, or `None` if the connection closed before sending one.
+// A body over `MAX_BODY` is an `InvalidData` error.
+pub fn read_request<R: BufRead>(input: &mut R) -> io::Result<Option<Request>> {
+    let mut request_line = String::new();
+    if input.read_line(&mut request_line)? == 0 {
+        return Ok(None);
+    }
+    let mut words = request_line.split_whitespace();
+    let method = words.next().unwrap_or_default().to_string();
+    let path = words.next().unwrap_or_default().to_string();
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            length = value.trim().parse().unwrap_or(0);
+        }
+    }
+    if length > MAX_BODY {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request body too large"));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(Request {
+        method,
+        path,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    }))
+}
+
+pub fn write_response<W: Write>(output: &mut W, response: &Response) -> io::Result<()> {
+    write!(
+        output,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    output.flush()
+}
+
+// The program being edited and debugged in the browser.
+pub struct Session {
+    int: Interpreter,
+    von_neumann: bool,
+    trace: Vec<Step>,
+    steps: usize,
+    error: Option<String>,
+}
+
+impl Session {
+    pub fn new(source: String, extensions: Extensions) -> Self {
+        let mut session = Session {
+            int: Interpreter::new(String::new()),
+            von_neumann: false,
+            trace: vec![],
+            steps: 0,
+            error: None,
+        };
+        session.assemble(source, false, extensions);
+        session
+    }
+
+    // Loads new source in a dialect, reporting the first line that would trap.
+    fn assemble(&mut self, source: String, von_neumann: bool, extensions: Extensions) {
+        self.int = Interpreter::new(source);
+        self.int.extensions = extensions;
+        self.int.reset();
+        self.von_neumann = von_neumann;
+        self.trace.clear();
+        self.steps = 0;
+        let trap = if von_neumann {
+            self.int.load_into_memory().err()
+        } else {
+            self.int.check().into_iter().next()
+        };
+        self.error = trap.map(|(line, trap)| format!("line {}: {}", line, trap));
+    }
+
+    fn reset(&mut self) {
+        self.int.reset();
+        self.trace.clear();
+        self.steps = 0;
+        self.error = None;
+    }
+
+    // Runs one instruction, or returns false if the program can't go on.
+    fn step(&mut self) -> bool {
+        if !self.int.running {
+            return false;
+        }
+        match trace::step(&mut self.int) {
+            Ok(None) if !self.int.running => false,
+            Ok(step) => {
+                self.steps += 1;
+                self.trace.extend(step);
+                self.int.running
+            }
+            Err(trap) => {
+                let line = self.int.source_line(self.int.line_num).unwrap_or(self.int.line_num);
+                self.error = Some(format!("line {}: {}", line, trap));
+                false
+            }
+        }
+    }
+
+    fn run(&mut self) {
+        for _ in 0..RUN_LIMIT {
+            if !self.step() {
+                return;
+            }
+        }
+        self.error = Some(format!("stopped after {} steps without halting", RUN_LIMIT));
+    }
+
+    pub fn state(&self) -> Json {
+        let trace = &self.trace[self.trace.len().saturating_sub(TRACE_ROWS)..];
+        object([
+            ("source", self.int.source.iter().map(|line| Json::from(line.as_str())).collect::<Vec<_>>().into()),
+            ("vonNeumann", self.von_neumann.into()),
+            ("dialect", self.int.extensions.to_string().into()),
+            ("pc", self.int.line_num.into()),
+            ("line", self.int.source_line(self.int.line_num).into()),
+            ("running", self.int.running.into()),
+            ("compare", format!("{:?}", self.int.cmp).into()),
+            ("registers", self.int.reg.iter().map(|&value| Json::from(value)).collect::<Vec<_>>().into()),
+            ("memory", self.int.mem.iter().map(|&value| Json::from(value)).collect::<Vec<_>>().into()),
+            ("steps", self.steps.into()),
+            ("traceRows", self.trace.len().into()),
+            (
+                "trace",
+                trace
+                    .iter()
+                    .map(|step| {
+                        object([
+                            ("line", step.line.into()),
+                            ("instruction", step.instruction.as_str().into()),
+                            ("changes", step.changes().into()),
+                        ])
+                    })
+                    .collect::<Vec<_>>()
+                    .into(),
+            ),
+            ("error", self.error.clone().into()),
+        ])
+    }
+
+    pub fn respond(&mut self, request: &Request) -> Response {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/") => Response {
+                status: "200 OK",
+                content_type: "text/html; charset=utf-8",
+                body: PAGE.to_string(),
+            },
+            ("GET", "/api/state") => Response::json(self.state()),
+            ("POST", "/api/assemble") => {
+                let Ok(body) = Json::parse(&request.body) else {
+                    return Response::error("400 Bad Request");
+                };
+                let Some(source) = body.get("source").and_then(Json::as_str) else {
+                    return Response::error("400 Bad Request");
+                };
+                let von_neumann = body.get("vonNeumann").and_then(Json::as_bool).unwrap_or(false);
+                // Without a dialect the program keeps the one it had.
+                let extensions = match body.get("dialect").and_then(Json::as_str).map(Extensions::parse) {
+                    Some(Ok(extensions)) => extensions,
+                    Some(Err(_)) => return Response::error("400 Bad Request"),
+                    None => self.int.extensions,
+                };
+                self.assemble(source.to_string(), von_neumann, extensions);
+                Response::json(self.state())
+            }
+            ("POST", "/api/step") => {
+                self.step();
+                Response::json(self.state())
+            }
+            ("POST", "/api/run") => {
+                self.run();
+                Response::json(self.state())
+            }
+            ("POST", "/api/reset") => {
+                self.reset();
+                Response::json(self.state())
+            }
+            _ => Response::error("404 Not Found"),
+        }
+    }
+}
+
+// Serves the editor on localhost until the process is stopped. Requests are
+// handled one at a time, so every browser tab shares the same session.
+pub fn run(source: String, extensions: Extensions, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Serving on http://127.0.0.1:{}/", listener.local_addr()?.port());
+    let mut session = Session::new(source, extensions);
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let response = match read_request(&mut BufReader::new(&stream)) {
+            Ok(Some(request)) => session.respond(&request),
+            Ok(None) => continue,
+            Err(error) if error.kind() == io::ErrorKind::InvalidData => Response::error("413 Payload Too Large"),
+            Err(error) => {
+                eprintln!("{}", error);
+                continue;
+            }
+        };
+        if let Err(error) = write_response(&mut stream, &response) {
+            eprintln!("{}", error);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(session: &mut Session, path: &str, body: &str) -> Json {
+        let request = Request {
+            method: String::from("POST"),
+            path: path.to_string(),
+            body: body.to_string(),
+        };
+        let response = session.respond(&request);
+        assert_eq!(response.status, "200 OK");
+        Json::parse(&response.body).unwrap()
+    }
+
+    #[test]
+    fn requests() {
+        let mut input = "POST /api/run HTTP/1.1\r\nHost: x\r\nContent-length: 4\r\n\r\n{}\r\nrest".as_bytes();
+        let request = read_request(&mut input).unwrap().unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str(), request.body.as_str()), ("POST", "/api/run", "{}\r\n"));
+        assert!(read_request(&mut "".as_bytes()).unwrap().is_none());
+        let mut input = "POST /api/run HTTP/1.1\r\nContent-Length: 999999999999999999\r\n\r\n".as_bytes();
+        assert_eq!(read_request(&mut input).err().map(|error| error.kind()), Some(io::ErrorKind::InvalidData));
+
+        let mut output = Vec::new();
+        write_response(&mut output, &Response::error("404 Not Found")).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(output.ends_with("Content-Length: 13\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n404 Not Found"));
+    }
+
+    #[test]
+    fn step_and_run() {
+        let mut session = Session::new(String::from("MOV R0, #2\nloop:\nSUB R0, R0, #1\nCMP R0, #0\nBGT loop\nHALT"), Extensions::default());
+        let state = post(&mut session, "/api/step", "");
+        assert_eq!(state.get("registers").and_then(Json::as_array).map(|reg| reg[0].clone()), Some(Json::from(2)));
+        assert_eq!(state.get("line"), Some(&Json::from(1)));
+        let state = post(&mut session, "/api/run", "");
+        assert_eq!(state.get("running"), Some(&Json::from(false)));
+        assert_eq!(state.get("traceRows"), Some(&Json::from(8)));
+        let trace = state.get("trace").and_then(Json::as_array).unwrap();
+        assert_eq!(trace[1].get("changes").and_then(Json::as_str), Some("R0 = 1"));
+        assert_eq!(trace[2].get("instruction").and_then(Json::as_str), Some("CMP R0, #0"));
+
+        let state = post(&mut session, "/api/reset", "");
+        assert_eq!(state.get("steps"), Some(&Json::from(0)));
+        assert_eq!(state.get("running"), Some(&Json::from(true)));
+
+        let mut session = Session::new(String::from("MOV R0, #1\nADD R0, R0, #1"), Extensions::default());
+        let state = post(&mut session, "/api/run", "");
+        assert_eq!((state.get("steps"), state.get("traceRows")), (Some(&Json::from(2)), Some(&Json::from(2))));
+    }
+
+    #[test]
+    fn assemble() {
+        let mut session = Session::new(String::new(), Extensions::default());
+        let state = post(&mut session, "/api/assemble", r#"{"source": "MOV R1, #7\nHALT", "vonNeumann": true}"#);
+        assert_eq!(state.get("vonNeumann"), Some(&Json::from(true)));
+        assert_ne!(state.at(&["memory"]).and_then(Json::as_array).map(|mem| mem[0].clone()), Some(Json::from(0)));
+
+        let state = post(&mut session, "/api/assemble", r#"{"source": "NOP\nLDR R0, 300"}"#);
+        assert_eq!(state.get("error").and_then(Json::as_str), Some("line 0: syntax error: unknown instruction 'NOP'"));
+
+        let state = post(&mut session, "/api/assemble", r#"{"source": "OUT R0", "dialect": "extended"}"#);
+        assert_eq!((state.get("dialect"), state.get("error")), (Some(&Json::from("extended")), Some(&Json::Null)));
+        let state = post(&mut session, "/api/assemble", r#"{"source": "OUT R0", "dialect": "aqa"}"#);
+        assert_eq!(state.get("error").and_then(Json::as_str), Some("line 0: syntax error: OUT needs the io extension"));
+
+        let state = post(&mut session, "/api/assemble", r#"{"source": "LDR R0, 300"}"#);
+        assert!(state.get("error").and_then(Json::as_str).is_some());
+        let state = post(&mut session, "/api/step", "");
+        assert!(state.get("error").and_then(Json::as_str).unwrap().starts_with("line 0: "));
+    }
+
+    #[test]
+    fn errors() {
+        let mut session = Session::new(String::new(), Extensions::default());
+        let request = Request {
+            method: String::from("POST"),
+            path: String::from("/api/assemble"),
+            body: String::from("not json"),
+        };
+        assert_eq!(session.respond(&request).status, "400 Bad Request");
+        let request = Request {
+            method: String::from("GET"),
+            path: String::from("/missing"),
+            body: String::new(),
+        };
+        assert_eq!(session.respond(&request).status, "404 Not Found");
+    }
+}
@@ -0,0 +1,383 @@
+use std::io::Write;
+
+use crate::Interpreter;
+use crate::Trap;
+use crate::console;
+use crate::console::Input;
+use crate::device::Devices;
+use crate::instruction::Extensions;
+use crate::json::Json;
+use crate::json::object;
+use crate::script::Location;
+use crate::script::parse_location;
+use crate::script::parse_value;
+
+// Used when neither the case nor the top of the file sets `limit`.
+pub const DEFAULT_LIMIT: usize = 10_000;
+
+pub struct Case {
+    pub name: String,
+    pub limit: usize,
+    extensions: Extensions,
+    // Device list as written in the spec; devices can't be cloned, so each
+    // run attaches fresh ones.
+    devices: Option<String>,
+    setup: Vec<(Location, i32)>,
+    expected: Vec<(Location, i32)>,
+    input: Vec<i32>,
+    // Lines the program must print, if the case checks its output.
+    output: Option<Vec<String>>,
+}
+
+pub enum Outcome {
+    Pass,
+    Fail(Vec<String>),
+    Trap(usize, Trap),
+    Timeout(usize),
+}
+
+fn cell(token: Option<&str>) -> Result<Location, String> {
+    match parse_location(token.ok_or("missing location")?)? {
+        location @ (Location::Reg(_) | Location::Mem(_)) => Ok(location),
+        _ => Err(String::from("only registers and memory can be used in test cases")),
+    }
+}
+
+fn name(location: &Location) -> String {
+    match location {
+        Location::Reg(register) => format!("R{}", register),
+        Location::Mem(address) => format!("mem {}", address),
+        _ => unreachable!(),
+    }
+}
+
+fn value(int: &Interpreter, location: &Location) -> i32 {
+    match location {
+        Location::Reg(register) => int.reg[*register],
+        Location::Mem(address) => int.mem[*address],
+        _ => unreachable!(),
+    }
+}
+
+// Parses a test specification. A `limit`, `dialect` (or `extensions`) or `devices` before
+// the first `case` becomes the default for every case in the file. Cases
+// that set no dialect use `dialect`.
+pub fn parse(text: &str, dialect: Extensions) -> Result<Vec<Case>, String> {
+    let mut cases: Vec<Case> = vec![];
+    let mut default_limit = DEFAULT_LIMIT;
+    let mut default_extensions = dialect;
+    let mut default_devices: Option<String> = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        let mut words = line.split_whitespace();
+        let error = |message: String| format!("test line {}: {}", index + 1, message);
+
+        match words.next() {
+            Some("case") => {
+                let name = words.collect::<Vec<&str>>().join(" ");
+                if name.is_empty() {
+                    return Err(error(String::from("missing case name")));
+                }
+                cases.push(Case {
+                    name,
+                    limit: default_limit,
+                    extensions: default_extensions,
+                    devices: default_devices.clone(),
+                    setup: vec![],
+                    expected: vec![],
+                    input: vec![],
+                    output: None,
+                });
+            }
+
+            Some("extensions" | "dialect") => {
+                let extensions = Extensions::parse(&words.collect::<Vec<&str>>().join(",")).map_err(error)?;
+                match cases.last_mut() {
+                    Some(case) => case.extensions = extensions,
+                    None => default_extensions = extensions,
+                }
+            }
+
+            Some("devices") => {
+                let devices = line["devices".len()..].trim().to_string();
+                Devices::parse(&devices).map_err(error)?;
+                match cases.last_mut() {
+                    Some(case) => case.devices = Some(devices),
+                    None => default_devices = Some(devices),
+                }
+            }
+
+            Some(directive @ ("input" | "output")) => {
+                let case = cases
+                    .last_mut()
+                    .ok_or_else(|| error(format!("'{}' outside a case", directive)))?;
+                let rest = line[directive.len()..].trim();
+                if directive == "input" {
+                    case.input.extend(console::parse_values(rest).map_err(error)?);
+                } else {
+                    case.output.get_or_insert_with(Vec::new).push(rest.to_string());
+                }
+            }
+
+            Some(directive @ ("limit" | "set" | "expect")) => {
+                if directive == "limit" {
+                    let limit = words.next().ok_or_else(|| error(String::from("missing step limit")))?;
+                    let limit = limit
+                        .parse::<usize>()
+                        .map_err(|_| error(format!("invalid step limit '{}'", limit)))?;
+                    match cases.last_mut() {
+                        Some(case) => case.limit = limit,
+                        None => default_limit = limit,
+                    }
+                    continue;
+                }
+
+                let case = cases
+                    .last_mut()
+                    .ok_or_else(|| error(format!("'{}' outside a case", directive)))?;
+                let location = cell(words.next()).map_err(error)?;
+                let value = parse_value(words.next()).map_err(error)?;
+                if directive == "set" {
+                    case.setup.push((location, value));
+                } else {
+                    case.expected.push((location, value));
+                }
+            }
+
+            Some(directive) => return Err(error(format!("unknown directive '{}'", directive))),
+            None => (),
+        }
+    }
+
+    Ok(cases)
+}
+
+// Every extension any case enables, for checking a program before it runs.
+pub fn dialect(cases: &[Case]) -> Extensions {
+    cases
+        .iter()
+        .fold(Extensions::default(), |dialect, case| dialect.union(case.extensions))
+}
+
+// Resets `int`, applies the case's initial state and runs it to `HALT`.
+// Extensions and devices are set on every case, so nothing carries over from
+// the case run before it.
+pub fn run_case(int: &mut Interpreter, case: &Case) -> Outcome {
+    int.extensions = case.extensions;
+    int.devices = case.devices.as_deref().map_or_else(Devices::default, |devices| Devices::parse(devices).unwrap());
+    int.reset();
+    int.console.set_input(Input::Values(case.input.clone()));
+    for (location, value) in &case.setup {
+        match location {
+            Location::Reg(register) => int.reg[*register] = *value,
+            Location::Mem(address) => int.mem[*address] = *value,
+            _ => unreachable!(),
+        }
+    }
+
+    let mut steps = 0;
+    while int.running {
+        if steps == case.limit {
+            return Outcome::Timeout(case.limit);
+        }
+        if let Err(trap) = int.tick() {
+            return Outcome::Trap(int.line_num, trap);
+        }
+        steps += 1;
+    }
+
+    let mut mismatches: Vec<String> = case
+        .expected
+        .iter()
+        .filter(|(location, expected)| value(int, location) != *expected)
+        .map(|(location, expected)| {
+            format!("{}: expected {}, got {}", name(location), expected, value(int, location))
+        })
+        .collect();
+    if let Some(expected) = &case.output {
+        let output: Vec<&str> = int.console.output.lines().collect();
+        if output != *expected {
+            mismatches.push(format!("output: expected {:?}, got {:?}", expected.join("\n"), output.join("\n")));
+        }
+    }
+
+    if mismatches.is_empty() {
+        Outcome::Pass
+    } else {
+        Outcome::Fail(mismatches)
+    }
+}
+
+// The indented lines explaining why a case failed, empty for a pass.
+pub fn details(outcome: &Outcome) -> Vec<String> {
+    match outcome {
+        Outcome::Pass => vec![],
+        Outcome::Fail(mismatches) => mismatches.clone(),
+        Outcome::Trap(line, trap) => vec![format!("trap at line {}: {}", line, trap)],
+        Outcome::Timeout(limit) => vec![format!("step limit of {} reached before HALT", limit)],
+    }
+}
+
+pub fn report<W: Write>(case: &Case, outcome: &Outcome, out: &mut W) {
+    if let Outcome::Pass = outcome {
+        writeln!(out, "PASS {}", case.name).unwrap();
+        return;
+    }
+    writeln!(out, "FAIL {}", case.name).unwrap();
+    for line in details(outcome) {
+        writeln!(out, "    {}", line).unwrap();
+    }
+}
+
+fn case_json(case: &Case, outcome: &Outcome) -> Json {
+    let (kind, line) = match outcome {
+        Outcome::Pass => ("pass", None),
+        Outcome::Fail(_) => ("fail", None),
+        Outcome::Trap(line, _) => ("trap", Some(*line)),
+        Outcome::Timeout(_) => ("timeout", None),
+    };
+    object([
+        ("name", case.name.as_str().into()),
+        ("outcome", kind.into()),
+        ("line", line.into()),
+        ("details", details(outcome).into_iter().map(Json::from).collect::<Vec<_>>().into()),
+    ])
+}
+
+fn outcomes(source: &str, spec: &str, dialect: Extensions) -> Result<Vec<(Case, Outcome)>, String> {
+    let mut int = Interpreter::new(source.to_string());
+    Ok(parse(spec, dialect)?
+        .into_iter()
+        .map(|case| {
+            let outcome = run_case(&mut int, &case);
+            (case, outcome)
+        })
+        .collect())
+}
+
+// Runs every case in `spec` against `source`, in `dialect` unless the spec
+// sets its own. Returns whether all of them passed.
+pub fn run<W: Write>(source: &str, spec: &str, dialect: Extensions, out: &mut W) -> Result<bool, String> {
+    let outcomes = outcomes(source, spec, dialect)?;
+    for (case, outcome) in &outcomes {
+        report(case, outcome, out);
+    }
+    let passed = outcomes.iter().filter(|(_, outcome)| matches!(outcome, Outcome::Pass)).count();
+    writeln!(out, "{}/{} cases passed", passed, outcomes.len()).unwrap();
+    Ok(passed == outcomes.len())
+}
+
+// As `run`, but writes the results as JSON.
+pub fn run_json<W: Write>(source: &str, spec: &str, dialect: Extensions, out: &mut W) -> Result<bool, String> {
+    let outcomes = outcomes(source, spec, dialect)?;
+    let passed = outcomes.iter().filter(|(_, outcome)| matches!(outcome, Outcome::Pass)).count();
+    let json = object([
+        ("passed", passed.into()),
+        ("total", outcomes.len().into()),
+        ("cases", outcomes.iter().map(|(case, outcome)| case_json(case, outcome)).collect::<Vec<_>>().into()),
+    ]);
+    writeln!(out, "{}", json).unwrap();
+    Ok(passed == outcomes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOUBLE: &str = "LDR R0, 40\nADD R0, R0, R0\nSTR R0, 41\nHALT";
+
+    fn outcome(source: &str, spec: &str) -> (bool, String) {
+        let mut out = Vec::new();
+        let passed = run(source, spec, Extensions::default(), &mut out).unwrap();
+        (passed, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn passing_cases() {
+        let spec = "case small\nset 40 2\nexpect 41 4\nexpect R0 4\n\ncase negative\nset 40 -3\nexpect 41 -6";
+        let (passed, out) = outcome(DOUBLE, spec);
+        assert!(passed);
+        assert_eq!(out, "PASS small\nPASS negative\n2/2 cases passed\n");
+    }
+
+    #[test]
+    fn failing_case_shows_diff() {
+        let (passed, out) = outcome(DOUBLE, "case wrong\nset 40 5\nexpect 41 11\nexpect R0 10");
+        assert!(!passed);
+        assert_eq!(out, "FAIL wrong\n    mem 41: expected 11, got 10\n0/1 cases passed\n");
+    }
+
+    #[test]
+    fn timeout_and_trap() {
+        let (passed, out) = outcome("loop:\nB loop", "limit 50\ncase spins\nexpect R0 0");
+        assert!(!passed);
+        assert!(out.contains("step limit of 50 reached before HALT"));
+
+        let (passed, out) = outcome("LDR R0, 999\nHALT", "case traps\nexpect R0 0");
+        assert!(!passed);
+        assert!(out.contains("trap at line 0: memory address '999' out of range"));
+    }
+
+    #[test]
+    fn json_results() {
+        let mut out = Vec::new();
+        let passed = run_json(DOUBLE, "case ok\nset 40 1\nexpect 41 2\ncase bad\nexpect R0 1", Extensions::default(), &mut out).unwrap();
+        assert!(!passed);
+        let json = Json::parse(&String::from_utf8(out).unwrap()).unwrap();
+        assert_eq!((json.get("passed"), json.get("total")), (Some(&Json::from(1)), Some(&Json::from(2))));
+        assert_eq!(
+            json.get("cases").and_then(Json::as_array).unwrap()[1].to_string(),
+            r#"{"name":"bad","outcome":"fail","line":null,"details":["R0: expected 1, got 0"]}"#
+        );
+    }
+
+    #[test]
+    fn input_and_output() {
+        let source = "INP R0\nINP R1\nADD R2, R0, R1\nOUT R2\nMOV R3, #33\nOUTC R3\nHALT";
+        let spec = "extensions io\ncase adds\ninput 3, 4\noutput 7\noutput !\n\ncase wrong\ninput 1 1\noutput 3";
+        let (passed, out) = outcome(source, spec);
+        assert!(!passed);
+        assert_eq!(out, "PASS adds\nFAIL wrong\n    output: expected \"3\", got \"2\\n!\"\n1/2 cases passed\n");
+
+        let (_, out) = outcome(source, "extensions io\ncase starved\ninput 1");
+        assert!(out.contains("trap at line 1: input error: no input left"));
+
+        let devices = "LDR R0, 241\nADD R0, R0, #48\nSTR R0, 240\nHALT";
+        let (passed, _) = outcome(devices, "devices console, keyboard\ncase echoes a digit\ninput 5\noutput 5");
+        assert!(passed);
+
+        // Only the first case turns the extensions and devices on.
+        let spec = "case extended\ndialect extended\ndevices timer\ninput 1 2\ncase strict\ninput 1 2\nexpect R0 0";
+        let (_, out) = outcome("INP R0\nHALT", spec);
+        assert_eq!(out, "PASS extended\nFAIL strict\n    trap at line 0: syntax error: INP needs the io extension\n1/2 cases passed\n");
+        let (passed, _) = outcome("LDR R0, 242\nHALT", "case timer\ndevices timer\nexpect R0 0\ncase memory\nset 242 7\nexpect R0 7");
+        assert!(passed);
+
+        // A dialect given on the command line applies to cases that set none.
+        let mut out = Vec::new();
+        let spec = "case io\ninput 1\nexpect R0 1\ncase exam\ndialect aqa\ninput 1";
+        assert!(!run("INP R0\nHALT", spec, Extensions::parse("io").unwrap(), &mut out).unwrap());
+        assert!(String::from_utf8(out).unwrap().starts_with("PASS io\nFAIL exam\n"));
+
+        let (_, out) = outcome(source, "case strict\ninput 1 2");
+        assert!(out.contains("trap at line 0: syntax error: INP needs the io extension"));
+        let (passed, _) = outcome(source, "dialect aqa+io\ncase adds\ninput 3 4\noutput 7\noutput !");
+        assert!(passed);
+        let (_, out) = outcome(source, "dialect extended\ncase exam\ndialect aqa\ninput 1 2");
+        assert!(out.contains("INP needs the io extension"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse("set R0 1", Extensions::default()).err(), Some(String::from("test line 1: 'set' outside a case")));
+        assert_eq!(
+            parse("case a\nexpect cmp EQ", Extensions::default()).err(),
+            Some(String::from("test line 2: only registers and memory can be used in test cases"))
+        );
+        assert_eq!(parse("case a\nlimit x", Extensions::default()).err(), Some(String::from("test line 2: invalid step limit 'x'")));
+        assert_eq!(parse("input 1", Extensions::default()).err(), Some(String::from("test line 1: 'input' outside a case")));
+        assert_eq!(parse("extensions gpu", Extensions::default()).err(), Some(String::from("test line 1: unknown dialect or extension 'gpu'")));
+        assert_eq!(parse("devices lamp", Extensions::default()).err(), Some(String::from("test line 1: unknown device 'lamp'")));
+    }
+}
@@ -0,0 +1,242 @@
+use crate::Interpreter;
+use crate::Trap;
+use crate::instruction::code;
+use crate::json::Json;
+use crate::json::object;
+
+// One row of a trace table: an instruction that ran and what it changed.
+#[derive(PartialEq, Debug)]
+pub struct Step {
+    pub line: usize,
+    pub instruction: String,
+    pub registers: Vec<(usize, i32)>,
+    pub memory: Vec<(usize, i32)>,
+    pub compare: Option<String>,
+}
+
+impl Step {
+    // The changes as a trace table would write them, such as `R0 = 5, mem[40] = 5`.
+    pub fn changes(&self) -> String {
+        let registers = self.registers.iter().map(|(register, value)| format!("R{} = {}", register, value));
+        let memory = self.memory.iter().map(|(address, value)| format!("mem[{}] = {}", address, value));
+        let compare = self.compare.iter().map(|compare| format!("CMP = {}", compare));
+        registers.chain(memory).chain(compare).collect::<Vec<_>>().join(", ")
+    }
+
+    pub fn json(&self) -> Json {
+        let registers = self.registers.iter().map(|&(register, value)| {
+            object([("register", register.into()), ("value", value.into())])
+        });
+        let memory = self.memory.iter().map(|&(address, value)| {
+            object([("address", address.into()), ("value", value.into())])
+        });
+        object([
+            ("line", self.line.into()),
+            ("instruction", self.instruction.as_str().into()),
+            ("registers", registers.collect::<Vec<_>>().into()),
+            ("memory", memory.collect::<Vec<_>>().into()),
+            ("compare", self.compare.clone().into()),
+        ])
+    }
+}
+
+// How a program run from the command line ended.
+pub struct Run {
+    pub steps: usize,
+    pub trap: Option<(usize, Trap)>,
+    pub trace: Vec<Step>,
+}
+
+impl Run {
+    fn status(&self, int: &Interpreter) -> &'static str {
+        match self.trap {
+            Some(_) => "trap",
+            None if int.running => "timeout",
+            None => "halted",
+        }
+    }
+}
+
+// Runs one instruction, returning its row, or `None` for a label or blank line
+// or for running off the end of the program.
+pub fn step(int: &mut Interpreter) -> Result<Option<Step>, Trap> {
+    let reg = int.reg;
+    let mem = int.mem;
+    let cmp = format!("{:?}", int.cmp);
+    int.tick()?;
+    if int.fde.instruction.is_none() {
+        return Ok(None);
+    }
+    let line = int.source_line(int.fde.fetched).unwrap_or(int.fde.fetched);
+    let compare = format!("{:?}", int.cmp);
+    Ok(Some(Step {
+        line,
+        instruction: int.source.get(line).map_or("", |text| code(text)).to_string(),
+        registers: (0..reg.len()).filter(|&r| reg[r] != int.reg[r]).map(|r| (r, int.reg[r])).collect(),
+        memory: (0..mem.len()).filter(|&a| mem[a] != int.mem[a]).map(|a| (a, int.mem[a])).collect(),
+        compare: (compare != cmp).then_some(compare),
+    }))
+}
+
+// Runs until the program halts, traps or takes `limit` steps, keeping the
+// trace table if asked to.
+pub fn run(int: &mut Interpreter, limit: usize, record: bool) -> Run {
+    let mut run = Run {
+        steps: 0,
+        trap: None,
+        trace: vec![],
+    };
+    while int.running && run.steps < limit {
+        match step(int) {
+            // Running off the end finds nothing to execute, so isn't a step.
+            Ok(None) if !int.running => break,
+            Ok(row) => {
+                run.steps += 1;
+                if record {
+                    run.trace.extend(row);
+                }
+            }
+            Err(trap) => {
+                run.trap = Some((int.source_line(int.line_num).unwrap_or(int.line_num), trap));
+                break;
+            }
+        }
+    }
+    run
+}
+
+// The machine state every JSON report shares.
+pub fn state(int: &Interpreter) -> Json {
+    object([
+        ("dialect", int.extensions.to_string().into()),
+        ("pc", int.line_num.into()),
+        ("line", int.source_line(int.line_num).into()),
+        ("running", int.running.into()),
+        ("compare", format!("{:?}", int.cmp).into()),
+        ("registers", int.reg.iter().map(|&value| Json::from(value)).collect::<Vec<_>>().into()),
+        ("memory", int.mem.iter().map(|&value| Json::from(value)).collect::<Vec<_>>().into()),
+    ])
+}
+
+pub fn json(int: &Interpreter, run: &Run) -> Json {
+    let trap = run.trap.as_ref().map(|(line, trap)| {
+        object([("line", (*line).into()), ("message", trap.to_string().into())])
+    });
+    object([
+        ("status", run.status(int).into()),
+        ("steps", run.steps.into()),
+        ("trap", trap.into()),
+        ("state", state(int)),
+        ("output", int.console.output.as_str().into()),
+        ("trace", run.trace.iter().map(Step::json).collect::<Vec<_>>().into()),
+    ])
+}
+
+// The trace table, if one was kept, then how the run ended and what the
+// program left in registers and memory.
+pub fn report(int: &Interpreter, run: &Run) -> String {
+    let mut report = String::new();
+    // Start on a fresh line if the program's echoed output didn't end one.
+    if int.console.echo && !int.console.output.is_empty() && !int.console.output.ends_with('\n') {
+        report.push('\n');
+    }
+    if !run.trace.is_empty() {
+        let width = run.trace.iter().map(|step| step.instruction.len()).max().unwrap_or(0).max(11);
+        report.push_str(&format!("{:>5}  {:>4}  {:width$}  {}\n", "step", "line", "instruction", "changes"));
+        for (index, step) in run.trace.iter().enumerate() {
+            let row = format!("{:>5}  {:>4}  {:width$}  {}", index + 1, step.line, step.instruction, step.changes());
+            report.push_str(row.trim_end());
+            report.push('\n');
+        }
+        report.push('\n');
+    }
+    match (&run.trap, run.status(int)) {
+        (Some((line, trap)), _) => report.push_str(&format!("line {}: {}\n", line, trap)),
+        (None, "timeout") => report.push_str(&format!("Stopped after {} steps without halting\n", run.steps)),
+        _ => report.push_str(&format!("Halted after {} steps\n", run.steps)),
+    }
+    for (register, value) in int.reg.iter().enumerate().filter(|(_, value)| **value != 0) {
+        report.push_str(&format!("R{} = {}\n", register, value));
+    }
+    for (address, value) in int.mem.iter().enumerate().filter(|(_, value)| **value != 0) {
+        report.push_str(&format!("mem[{}] = {}\n", address, value));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_changes() {
+        let mut int = Interpreter::new(String::from("start:\nMOV R0, #5 ; five\nSTR R0, 40\nCMP R0, #1\nHALT"));
+        assert_eq!(step(&mut int), Ok(None));
+        let mov = step(&mut int).unwrap().unwrap();
+        assert_eq!((mov.line, mov.instruction.as_str()), (1, "MOV R0, #5"));
+        assert_eq!(mov.changes(), "R0 = 5");
+        assert_eq!(step(&mut int).unwrap().unwrap().memory, vec![(40, 5)]);
+        assert_eq!(step(&mut int).unwrap().unwrap().changes(), "CMP = GT");
+        assert_eq!(step(&mut int).unwrap().unwrap().changes(), "");
+        assert!(!int.running);
+    }
+
+    #[test]
+    fn von_neumann() {
+        let mut int = Interpreter::new(String::from("loop:\nMOV R1, #1\nHALT"));
+        int.load_into_memory().unwrap();
+        let mov = step(&mut int).unwrap().unwrap();
+        assert_eq!((mov.line, mov.instruction.as_str()), (1, "MOV R1, #1"));
+    }
+
+    #[test]
+    fn runs() {
+        let mut int = Interpreter::new(String::from("MOV R0, #2\nloop:\nSUB R0, R0, #1\nCMP R0, #0\nBGT loop\nSTR R0, 9\nHALT"));
+        let run = run(&mut int, 100, true);
+        assert_eq!((run.steps, run.trace.len()), (10, 9));
+        assert_eq!(
+            report(&int, &run).lines().take(3).collect::<Vec<_>>(),
+            [" step  line  instruction     changes", "    1     0  MOV R0, #2      R0 = 2", "    2     2  SUB R0, R0, #1  R0 = 1"]
+        );
+        assert!(report(&int, &run).ends_with("  HALT\n\nHalted after 10 steps\n"));
+
+        let json = json(&int, &run);
+        assert_eq!(json.get("status").and_then(Json::as_str), Some("halted"));
+        assert_eq!(json.at(&["state", "running"]), Some(&Json::from(false)));
+        assert_eq!(json.at(&["state", "dialect"]), Some(&Json::from("aqa")));
+        assert_eq!(
+            json.get("trace").and_then(Json::as_array).map(|trace| trace[0].to_string()),
+            Some(String::from(r#"{"line":0,"instruction":"MOV R0, #2","registers":[{"register":0,"value":2}],"memory":[],"compare":null}"#))
+        );
+    }
+
+    #[test]
+    fn stops() {
+        let mut int = Interpreter::new(String::from("MOV R3, #1\nloop:\nB loop"));
+        let spun = run(&mut int, 10, false);
+        assert!(spun.trace.is_empty());
+        assert_eq!(report(&int, &spun), "Stopped after 10 steps without halting\nR3 = 1\n");
+        assert_eq!(json(&int, &spun).get("status").and_then(Json::as_str), Some("timeout"));
+
+        let mut int = Interpreter::new(String::from("MOV R0, #1\nLDR R0, 300"));
+        let trapped = run(&mut int, 10, false);
+        assert_eq!(report(&int, &trapped), "line 1: memory address '300' out of range\nR0 = 1\n");
+        assert_eq!(json(&int, &trapped).at(&["trap", "line"]), Some(&Json::from(1)));
+    }
+
+    #[test]
+    fn without_halt() {
+        let mut int = Interpreter::new(String::from("MOV R0, #1\nADD R0, R0, #1"));
+        let run = run(&mut int, 10, true);
+        assert_eq!((run.steps, run.trace.len()), (2, 2));
+        assert!(report(&int, &run).ends_with("R0 = 2\n\nHalted after 2 steps\nR0 = 2\n"));
+        assert_eq!(json(&int, &run).get("steps"), Some(&Json::from(2)));
+        assert_eq!(step(&mut int), Ok(None));
+    }
+
+    #[test]
+    fn traps() {
+        let mut int = Interpreter::new(String::from("LDR R0, 300"));
+        assert!(step(&mut int).is_err());
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::HashSet;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::process::Command;
+use std::process::Stdio;
+
+use crate::Interpreter;
+
+// Stops `r` from hanging the terminal on a program that never halts.
+const RUN_LIMIT: usize = 100_000;
+const REG_CELL: usize = 18;
+const MEM_CELL: usize = 12;
+
+#[derive(PartialEq, Debug)]
+enum Key {
+    Char(char),
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Unknown,
+}
+
+struct Terminal {
+    saved: String,
+}
+
+impl Terminal {
+    fn enter() -> Self {
+        let saved = stty(&["-g"]).trim().to_string();
+        stty(&["-icanon", "-echo", "-isig", "min", "1"]);
+        print!("\x1b[?1049h\x1b[?25l");
+        io::stdout().flush().unwrap();
+        Self { saved }
+    }
+
+    fn size() -> (usize, usize) {
+        let size = stty(&["size"]);
+        let mut dims = size.split_whitespace().filter_map(|n| n.parse().ok());
+        match (dims.next(), dims.next()) {
+            (Some(rows), Some(cols)) if rows > 0 && cols > 0 => (rows, cols),
+            _ => (24, 80),
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        stty(&[&self.saved]);
+    }
+}
+
+fn stty(args: &[&str]) -> String {
+    Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        .unwrap_or_default()
+}
+
+fn parse_key(bytes: &[u8]) -> Key {
+    match bytes {
+        [0x1b, b'[', b'A', ..] => Key::Up,
+        [0x1b, b'[', b'B', ..] => Key::Down,
+        [0x1b, b'[', b'5', b'~', ..] => Key::PageUp,
+        [0x1b, b'[', b'6', b'~', ..] => Key::PageDown,
+        [0x03, ..] => Key::Char('q'),
+        [byte, ..] if byte.is_ascii() && *byte != 0x1b => Key::Char(*byte as char),
+        _ => Key::Unknown,
+    }
+}
+
+// Pads or truncates to exactly `width` characters so panes never bleed into each other.
+fn fit(text: &str, width: usize) -> String {
+    let mut fitted: String = text.chars().take(width).collect();
+    let len = fitted.chars().count();
+    fitted.extend(std::iter::repeat_n(' ', width - len));
+    fitted
+}
+
+struct Tui {
+    int: Interpreter,
+    name: String,
+    breakpoints: HashSet<usize>,
+    cursor: usize,
+    mem_scroll: usize,
+    prev_reg: [i32; 16],
+    prev_mem: [i32; 256],
+    status: String,
+}
+
+impl Tui {
+    fn new(int: Interpreter, name: String) -> Self {
+        Self {
+            prev_reg: int.reg,
+            prev_mem: int.mem,
+            int,
+            name,
+            breakpoints: HashSet::new(),
+            cursor: 0,
+            mem_scroll: 0,
+            status: String::from("Ready"),
+        }
+    }
+
+    // The source line the PC is on, which differs from the PC itself once the
+    // program has been assembled into memory.
+    fn current_line(&self) -> Option<usize> {
+        self.int.source_line(self.int.line_num)
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        self.current_line().is_some_and(|line| self.breakpoints.contains(&line))
+    }
+
+    fn follow_pc(&mut self) {
+        if let Some(line) = self.current_line() {
+            self.cursor = line;
+        }
+    }
+
+    fn snapshot(&mut self) {
+        self.prev_reg = self.int.reg;
+        self.prev_mem = self.int.mem;
+    }
+
+    fn step(&mut self) {
+        self.snapshot();
+        if !self.int.running {
+            self.status = String::from("Program halted, press x to reset");
+            return;
+        }
+        if let Err(trap) = self.int.tick() {
+            self.status = format!("Trap at line {}: {}", self.int.line_num, trap);
+            return;
+        }
+        self.follow_pc();
+        self.status = if self.int.running {
+            String::from("Stepped one line")
+        } else {
+            String::from("Program halted")
+        };
+    }
+
+    fn micro_step(&mut self) {
+        self.snapshot();
+        if !self.int.running {
+            self.status = String::from("Program halted, press x to reset");
+            return;
+        }
+        let phase = self.int.fde.phase;
+        if let Err(trap) = self.int.micro_tick() {
+            self.status = format!("Trap at line {}: {}", self.int.line_num, trap);
+            return;
+        }
+        if let Some(line) = self.int.source_line(self.int.fde.fetched) {
+            self.cursor = line;
+        }
+        self.status = format!("{:?} phase done", phase);
+    }
+
+    fn run(&mut self) {
+        self.snapshot();
+        if !self.int.running {
+            self.status = String::from("Program halted, press x to reset");
+            return;
+        }
+        let mut steps = 0;
+        while self.int.running && steps < RUN_LIMIT {
+            if let Err(trap) = self.int.tick() {
+                self.follow_pc();
+                self.status = format!("Trap at line {}: {}", self.int.line_num, trap);
+                return;
+            }
+            steps += 1;
+            if self.at_breakpoint() {
+                break;
+            }
+        }
+        self.follow_pc();
+        self.status = if !self.int.running {
+            format!("Program halted after {} steps", steps)
+        } else if self.at_breakpoint() {
+            format!("Breakpoint hit at line {}", self.cursor)
+        } else {
+            format!("Paused after {} steps", steps)
+        };
+    }
+
+    fn toggle_breakpoint(&mut self) {
+        if self.breakpoints.remove(&self.cursor) {
+            self.status = format!("Breakpoint cleared at line {}", self.cursor);
+        } else {
+            self.breakpoints.insert(self.cursor);
+            self.status = format!("Breakpoint set at line {}", self.cursor);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.int.reset();
+        self.snapshot();
+        self.cursor = 0;
+        self.status = String::from("Interpreter reset");
+    }
+
+    // Returns false once the user asks to quit.
+    fn handle(&mut self, key: Key) -> bool {
+        let last_line = self.int.source.len().saturating_sub(1);
+        match key {
+            Key::Char('q') => return false,
+            Key::Char('s') | Key::Char(' ') => self.step(),
+            Key::Char('m') => self.micro_step(),
+            Key::Char('r') => self.run(),
+            Key::Char('b') => self.toggle_breakpoint(),
+            Key::Char('x') => self.reset(),
+            Key::Up => self.cursor = self.cursor.saturating_sub(1),
+            Key::Down => self.cursor = (self.cursor + 1).min(last_line),
+            Key::PageUp => self.mem_scroll = self.mem_scroll.saturating_sub(1),
+            Key::PageDown => self.mem_scroll += 1,
+            _ => (),
+        }
+        true
+    }
+
+    fn render(&mut self, rows: usize, cols: usize) -> String {
+        let mut frame = String::from("\x1b[H\x1b[2J");
+        let rows = rows.max(10);
+        let left_width = (cols / 2).clamp(20, 60).min(cols);
+        let right_col = left_width + 2;
+        let right_width = cols.saturating_sub(right_col);
+        let body_rows = rows - 3;
+
+        let state = if self.int.running { "RUNNING" } else { "HALTED" };
+        let mode = if self.int.image.is_some() { "von Neumann" } else { "Harvard" };
+        let header = format!(
+            " ian  {}  PC {}  cmp {:?}  {}  {}  {}",
+            self.name, self.int.line_num, self.int.cmp, state, mode, self.int.extensions
+        );
+        frame.push_str(&format!("\x1b[1;1H\x1b[7m{}\x1b[0m", fit(&header, cols)));
+
+        // Source pane, scrolled to keep the cursor in view.
+        let top = self
+            .cursor
+            .saturating_sub(body_rows / 2)
+            .min(self.int.source.len().saturating_sub(body_rows));
+        for row in 0..body_rows {
+            let index = top + row;
+            let Some(text) = self.int.source.get(index) else {
+                break;
+            };
+            let marker = if self.breakpoints.contains(&index) { '*' } else { ' ' };
+            let current = self.current_line() == Some(index);
+            let arrow = if current { '>' } else { ' ' };
+            let line = fit(&format!("{}{}{:>4} {}", marker, arrow, index, text), left_width);
+            let style = if current {
+                "\x1b[7m"
+            } else if index == self.cursor {
+                "\x1b[4m"
+            } else {
+                ""
+            };
+            frame.push_str(&format!("\x1b[{};1H{}{}\x1b[0m", row + 2, style, line));
+        }
+
+        let mut row = 2;
+        frame.push_str(&format!("\x1b[{};{}H\x1b[1m{}\x1b[0m", row, right_col, fit("Registers", right_width)));
+        row += 1;
+        let per_row = (right_width / REG_CELL).clamp(1, 4);
+        for chunk in (0..16).collect::<Vec<usize>>().chunks(per_row) {
+            frame.push_str(&format!("\x1b[{};{}H", row, right_col));
+            for &r in chunk {
+                let cell = fit(&format!("R{:<2} {:>11}", r, self.int.reg[r]), REG_CELL);
+                if self.int.reg[r] != self.prev_reg[r] {
+                    frame.push_str(&format!("\x1b[1m{}\x1b[0m", cell));
+                } else {
+                    frame.push_str(&cell);
+                }
+            }
+            row += 1;
+        }
+        let mut compare = format!("CMP {:?}", self.int.cmp);
+        let calls = self.int.call_stack();
+        if !calls.is_empty() {
+            let lines: Vec<String> = calls.iter().map(ToString::to_string).collect();
+            compare.push_str(&format!("  returns to {}", lines.join(", ")));
+        }
+        frame.push_str(&format!("\x1b[{};{}H{}", row, right_col, fit(&compare, right_width)));
+        row += 2;
+
+        let fde = &self.int.fde;
+        let registers = format!("PC {}  MAR {}  ACC {}  next {:?}", self.int.line_num, fde.mar, fde.acc, fde.phase);
+        frame.push_str(&format!("\x1b[{};{}H{}", row, right_col, fit(&registers, right_width)));
+        frame.push_str(&format!("\x1b[{};{}H{}", row + 1, right_col, fit(&format!("MDR {}", fde.mdr), right_width)));
+        frame.push_str(&format!("\x1b[{};{}H{}", row + 2, right_col, fit(&format!("CIR {}", fde.cir), right_width)));
+        row += 4;
+
+        frame.push_str(&format!("\x1b[{};{}H\x1b[1m{}\x1b[0m", row, right_col, fit("Memory", right_width)));
+        row += 1;
+        let per_row = (right_width.saturating_sub(5) / MEM_CELL).clamp(1, 8);
+        let mem_rows = 256_usize.div_ceil(per_row);
+        let visible = (body_rows + 2).saturating_sub(row);
+        self.mem_scroll = self.mem_scroll.min(mem_rows.saturating_sub(visible));
+        for mem_row in self.mem_scroll..(self.mem_scroll + visible).min(mem_rows) {
+            let start = mem_row * per_row;
+            frame.push_str(&format!("\x1b[{};{}H{:>3}: ", row, right_col, start));
+            for address in start..(start + per_row).min(256) {
+                let cell = format!("{:>11} ", self.int.mem[address]);
+                if self.int.mem[address] != self.prev_mem[address] {
+                    frame.push_str(&format!("\x1b[1m{}\x1b[0m", cell));
+                } else {
+                    frame.push_str(&cell);
+                }
+            }
+            row += 1;
+        }
+
+        frame.push_str(&format!("\x1b[{};1H{}", rows - 1, fit(&self.status, cols)));
+        let help = " s step  m micro-step  r run  b breakpoint  x reset  Up/Down move  PgUp/PgDn memory  q quit";
+        frame.push_str(&format!("\x1b[{};1H\x1b[7m{}\x1b[0m", rows, fit(help, cols)));
+        frame
+    }
+}
+
+pub fn run(int: Interpreter, name: &str) {
+    let mut tui = Tui::new(int, name.to_string());
+    let _terminal = Terminal::enter();
+    let mut stdin = io::stdin();
+    let mut buf = [0; 8];
+    loop {
+        let (rows, cols) = Terminal::size();
+        let frame = tui.render(rows, cols);
+        let mut stdout = io::stdout();
+        stdout.write_all(frame.as_bytes()).unwrap();
+        stdout.flush().unwrap();
+
+        let read = stdin.read(&mut buf).unwrap();
+        if read == 0 || !tui.handle(parse_key(&buf[..read])) {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        assert_eq!(parse_key(b"s"), Key::Char('s'));
+        assert_eq!(parse_key(b"\x1b[A"), Key::Up);
+        assert_eq!(parse_key(b"\x1b[6~"), Key::PageDown);
+        assert_eq!(parse_key(&[0x03]), Key::Char('q'));
+        assert_eq!(parse_key(b"\x1b"), Key::Unknown);
+    }
+
+    #[test]
+    fn run_stops_at_breakpoint() {
+        let source = String::from("MOV R0, #1\nMOV R1, #2\nMOV R2, #3\nHALT");
+        let mut tui = Tui::new(Interpreter::new(source), String::from("test"));
+        tui.cursor = 2;
+        tui.toggle_breakpoint();
+        tui.run();
+        assert_eq!(tui.int.line_num, 2);
+        assert_eq!(tui.int.reg[1], 2);
+        tui.run();
+        assert!(!tui.int.running);
+        assert_eq!(tui.int.reg[2], 3);
+    }
+
+    #[test]
+    fn breakpoints_in_memory_mode() {
+        let source = String::from("MOV R0, #1\nloop:\nADD R0, R0, #1\nB loop");
+        let mut int = Interpreter::new(source);
+        int.load_into_memory().unwrap();
+        let mut tui = Tui::new(int, String::from("test"));
+        tui.cursor = 2;
+        tui.toggle_breakpoint();
+        tui.run();
+        tui.run();
+        assert_eq!((tui.int.line_num, tui.cursor), (1, 2));
+        assert_eq!(tui.int.reg[0], 2);
+    }
+
+    #[test]
+    fn render_shows_state() {
+        let source = String::from("MOV R0, #42\nHALT");
+        let mut tui = Tui::new(Interpreter::new(source), String::from("test"));
+        tui.step();
+        let frame = tui.render(24, 100);
+        assert!(frame.contains("MOV R0, #42"));
+        assert!(frame.contains("R0           42"));
+        assert!(frame.contains("cmp NONE"));
+        assert!(frame.contains("Harvard  aqa"));
+        assert!(frame.contains("CIR MOV R0, #42"));
+        assert!(frame.contains("ACC 42"));
+    }
+}